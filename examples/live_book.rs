@@ -0,0 +1,54 @@
+//! Prints the top of every book as the feed updates it, without trading.
+//!
+//! `cargo run --example live_book`
+
+use bomex::{autotrader::AutoTrader, book::Book, feed::Message, url, username::Username};
+use futures_util::StreamExt;
+use tokio_tungstenite::connect_async;
+
+fn print(book: &Book) {
+    let (bid, ask) = book.bbo();
+    let format = |level: Option<bomex::book::PriceLevel>| {
+        level.map_or(String::from("-"), |level| {
+            format!("{:?} x {:?}", level.volume, level.price)
+        })
+    };
+    println!(
+        "{}: {} | {}, {}",
+        book.product,
+        format(bid),
+        format(ask),
+        book.metrics(5),
+    );
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // The username only tells our own orders apart, nothing is sent
+    let mut trader = AutoTrader::new(Username::KLiang, String::new());
+    let (stream, _) = connect_async(url!("ws", FEED_RECOVERY_PORT, "information")).await?;
+    trader.recover().await?;
+    let mut products: Vec<_> = trader.books.keys().cloned().collect();
+    products.sort();
+    for product in products {
+        print(&trader.books[&product]);
+    }
+
+    let mut stream = stream.split().1;
+    while let Some(message) = stream.next().await {
+        let message: Message = serde_json::from_slice(&message?.into_data())?;
+        let product = message.product().map(str::to_string);
+        // Stops at a sequence gap rather than printing books that missed messages
+        if !trader.apply(message)? {
+            continue;
+        }
+        let Some(product) = product else {
+            continue;
+        };
+        match trader.books.get(&product) {
+            Some(book) => print(book),
+            None => println!("{product}: halted"),
+        }
+    }
+    Ok(())
+}
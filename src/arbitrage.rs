@@ -1,10 +1,32 @@
+//! Arbitrage between an index and the sum of its underlying futures.
+
 use crate::{
-    book::{Book, PriceLevel},
+    book::{Book, Position, PriceLevel},
+    config::{ArbitrageConfig, ConfigError, ConfigWatcher, IndexParams},
     observations::Station,
     order::{AddMessage, MessageType, OrderType},
-    types::{Price, Side, Volume},
+    strategy::{self, OrderIntent},
+    types::{Price, Side, TypeError, Volume},
+    watchdog::{BookStates, DisableReason, WatchdogConfig},
+};
+use serde::Deserialize;
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
-use std::cmp::Ordering;
+use tracing::{error, info, warn};
+
+macro_rules! index_enabled {
+    ($index:ident, $book_states:expr) => {
+        $index
+            .iter()
+            .all(|book| $book_states.is_enabled(&book.product))
+    };
+}
 
 #[derive(Default)]
 struct IndexTheo {
@@ -12,39 +34,246 @@ struct IndexTheo {
     index: PriceLevel,
 }
 
+/// Volume a book can still buy or sell without any fill taking its position beyond the limit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Headroom {
+    pub buy: Volume,
+    pub sell: Volume,
+}
+
+impl Headroom {
+    #[cfg(test)]
+    pub const UNLIMITED: Self = Headroom {
+        buy: Volume::MAX,
+        sell: Volume::MAX,
+    };
+
+    /// Counts our resting orders as if they were already filled
+    pub fn new(position: &Position, limit: i32) -> Self {
+        let headroom = |exposure: Volume, position: i64| {
+            let headroom = limit as i64 - position - exposure.0 as i64;
+            Volume(headroom.clamp(0, u32::MAX as i64) as u32)
+        };
+        Headroom {
+            buy: headroom(position.bid_exposure, position.position as i64),
+            sell: headroom(position.ask_exposure, -(position.position as i64)),
+        }
+    }
+
+    fn side(&self, side: Side) -> Volume {
+        match side {
+            Side::Buy => self.buy,
+            Side::Sell => self.sell,
+        }
+    }
+}
+
+/// How the volume of a leg spread over several price levels is sent
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Sweep {
+    /// A single IOC at the worst price reached
+    #[default]
+    WorstPrice,
+    /// An IOC at each price level
+    PerLevel,
+}
+
+/// What is done with an order price that is not a multiple of the tick size of its book
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OffGrid {
+    /// Drop the whole arb
+    #[default]
+    Reject,
+    /// Round the price onto the grid so the order still reaches every level it was meant to take
+    Round,
+}
+
+/// One product of an arb along with the levels it is expected to trade against
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leg {
+    pub product: String,
+    pub side: Side,
+    pub volume: Volume,
+    /// Volume expected to trade at each price level, best first
+    pub levels: Vec<PriceLevel>,
+}
+
+impl Leg {
+    /// Takes volume from the best levels on the other side of the book
+    fn new(book: &Book, side: Side, volume: Volume) -> Result<Self, TypeError> {
+        let other_side = if side == Side::Buy {
+            Side::Sell
+        } else {
+            Side::Buy
+        };
+        let mut levels = Vec::new();
+        let mut remaining = volume;
+        for PriceLevel {
+            price,
+            volume: level_volume,
+        } in book.others_depth(other_side)?
+        {
+            if remaining == 0 {
+                break;
+            }
+            let volume = remaining.min(level_volume);
+            remaining = remaining.checked_sub(volume)?;
+            levels.push(PriceLevel { price, volume });
+        }
+        assert_eq!(remaining, 0, "Leg must have enough volume in the book");
+        Ok(Leg {
+            product: book.product.clone(),
+            side,
+            volume,
+            levels,
+        })
+    }
+
+    /// Expected cash spent or received in hundredths of a dollar
+    pub fn notional(&self) -> i64 {
+        self.levels
+            .iter()
+            .map(|level| level.price.0 as i64 * level.volume.0 as i64)
+            .sum()
+    }
+
+    /// Expected average fill price in dollars
+    pub fn average_price(&self) -> f64 {
+        self.notional() as f64 / self.volume.0 as f64 / 100.0
+    }
+
+    pub fn worst_price(&self) -> Price {
+        self.levels
+            .last()
+            .expect("Legs have at least one level")
+            .price
+    }
+
+    /// Orders sweeping the levels of the leg, with their prices checked against the grid of its book
+    fn orders(&self, book: &Book, params: &IndexParams) -> Result<Vec<AddMessage>, String> {
+        let order = |price: Price, volume| {
+            let price = if book.on_grid(price) {
+                price
+            } else if params.off_grid == OffGrid::Round {
+                book.round_aggressive(self.side, price)
+                    .map_err(|err| format!("{} price {price:?} {err}", self.product))?
+            } else {
+                return Err(format!(
+                    "{} price {price:?} is not a multiple of its tick size {:?}",
+                    self.product,
+                    book.tick(),
+                ));
+            };
+            Ok(AddMessage {
+                message_type: MessageType::Add,
+                product: self.product.clone(),
+                price,
+                side: self.side,
+                volume,
+                order_type: OrderType::Ioc,
+            })
+        };
+        match params.sweep {
+            Sweep::WorstPrice => Ok(vec![order(self.worst_price(), self.volume)?]),
+            Sweep::PerLevel => self
+                .levels
+                .iter()
+                .map(|level| order(level.price, level.volume))
+                .collect(),
+        }
+    }
+}
+
+/// Index arb with the orders to send and what they are expected to achieve
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arb {
+    /// The index followed by its underlying
+    pub legs: Vec<Leg>,
+    pub orders: Vec<AddMessage>,
+}
+
+impl Arb {
+    /// Fails when an order would be sent at a price off the grid of its book
+    fn new(books: &[&Book], legs: Vec<Leg>, params: &IndexParams) -> Result<Self, String> {
+        let mut orders = Vec::new();
+        for (book, leg) in books.iter().zip(legs.iter()) {
+            orders.extend(leg.orders(book, params)?);
+        }
+        Ok(Arb { legs, orders })
+    }
+
+    /// Expected profit in hundredths of a dollar if every leg fills at its expected average price
+    pub fn expected_edge(&self) -> i64 {
+        self.legs
+            .iter()
+            .map(|leg| match leg.side {
+                Side::Buy => -leg.notional(),
+                Side::Sell => leg.notional(),
+            })
+            .sum()
+    }
+}
+
+impl Display for Arb {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "expected edge {:.2}:",
+            self.expected_edge() as f64 / 100.0
+        )?;
+        for leg in self.legs.iter() {
+            write!(
+                f,
+                " {:?} {} {:?} avg {:.2} worst {:?};",
+                leg.side,
+                leg.product,
+                leg.volume,
+                leg.average_price(),
+                leg.worst_price(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(PartialEq)]
 enum Strategy {
     BuyIndexSellUnderlying,
     BuyUnderlyingSellIndex,
 }
 
-fn find_arbs_for_side(index: &[&Book; 4], strategy: Strategy, credit: Price) -> Vec<AddMessage> {
-    let mut orders = Vec::new();
+fn find_arbs_for_side(
+    index: &[&Book; 4],
+    headroom: &[Headroom; 4],
+    strategy: Strategy,
+    params: &IndexParams,
+) -> Result<Option<Arb>, TypeError> {
     let mut underlying_level = [PriceLevel::default(); 3];
     let mut index_volume = Volume::default();
     let mut underlying_price = [Price::default(); 3];
     let mut index_price = Price::default();
     let mut underlying_volume = Volume::default();
     let mut index_theo = IndexTheo::default();
-    let mut book_iters = index.map(|book| {
-        let iter: Box<dyn Iterator<Item = (&Price, &Volume)>> =
-            if strategy == Strategy::BuyUnderlyingSellIndex && book.station_id == Station::Index
-                || strategy == Strategy::BuyIndexSellUnderlying && book.station_id != Station::Index
-            {
-                Box::new(book.bids.iter().rev())
-            } else {
-                Box::new(book.asks.iter())
-            };
-        iter
+    // Our own resting orders are not liquidity we can take
+    let [iter0, iter1, iter2, iter3] = index.map(|book| {
+        if strategy == Strategy::BuyUnderlyingSellIndex && book.station_id == Station::Index
+            || strategy == Strategy::BuyIndexSellUnderlying && book.station_id != Station::Index
+        {
+            book.others_depth(Side::Buy)
+        } else {
+            book.others_depth(Side::Sell)
+        }
     });
+    let mut book_iters = [iter0?, iter1?, iter2?, iter3?];
     'outer: loop {
         let mut underlying_min_volume = Volume::MAX;
         for (i, iter) in book_iters[..3].iter_mut().enumerate() {
             if underlying_level[i].volume == 0 {
-                if let Some((&price, &volume)) = iter.next() {
-                    let price_level = PriceLevel { price, volume };
+                if let Some(price_level) = iter.next() {
                     underlying_level[i] = price_level;
-                    underlying_min_volume = underlying_min_volume.min(volume);
+                    underlying_min_volume = underlying_min_volume.min(price_level.volume);
                 } else {
                     break 'outer;
                 }
@@ -57,7 +286,7 @@ fn find_arbs_for_side(index: &[&Book; 4], strategy: Strategy, credit: Price) ->
                 index_theo.theo = PriceLevel {
                     price: underlying_level
                         .iter()
-                        .fold(Price::default(), |a, c| a + c.price),
+                        .try_fold(Price::default(), |a, c| a.checked_add(c.price))?,
                     volume: underlying_min_volume,
                 };
             }
@@ -67,7 +296,7 @@ fn find_arbs_for_side(index: &[&Book; 4], strategy: Strategy, credit: Price) ->
                     .expect("There are 4 items in book_iters")
                     .next()
                 {
-                    index_theo.index = index.into();
+                    index_theo.index = index;
                 } else {
                     break 'outer;
                 }
@@ -80,23 +309,24 @@ fn find_arbs_for_side(index: &[&Book; 4], strategy: Strategy, credit: Price) ->
                 }
             {
                 if strategy == Strategy::BuyIndexSellUnderlying
-                    && index_theo.theo.price - index_theo.index.price <= credit
+                    && index_theo.theo.price.checked_sub(index_theo.index.price)? <= params.credit
                     || strategy == Strategy::BuyUnderlyingSellIndex
-                        && index_theo.index.price - index_theo.theo.price <= credit
+                        && index_theo.index.price.checked_sub(index_theo.theo.price)?
+                            <= params.credit
                 {
                     // Not enough credit
                     break 'outer;
                 }
                 index_price = index_theo.index.price;
                 let index_min_volume = Volume::min(index_theo.theo.volume, index_theo.index.volume);
-                index_volume += index_min_volume;
-                index_theo.theo.volume -= index_min_volume;
-                index_theo.index.volume -= index_min_volume;
+                index_volume = index_volume.checked_add(index_min_volume)?;
+                index_theo.theo.volume = index_theo.theo.volume.checked_sub(index_min_volume)?;
+                index_theo.index.volume = index_theo.index.volume.checked_sub(index_min_volume)?;
                 if index_theo.theo.volume == 0 {
-                    underlying_volume += underlying_min_volume;
+                    underlying_volume = underlying_volume.checked_add(underlying_min_volume)?;
                     for (i, level) in underlying_level.iter_mut().enumerate() {
                         underlying_price[i] = level.price;
-                        level.volume -= underlying_min_volume;
+                        level.volume = level.volume.checked_sub(underlying_min_volume)?;
                     }
                     break;
                 }
@@ -110,72 +340,216 @@ fn find_arbs_for_side(index: &[&Book; 4], strategy: Strategy, credit: Price) ->
         index_volume, underlying_volume,
         "Arbs must have the same volume",
     );
-    if index_volume != 0 {
-        let theo_price = underlying_price
-            .iter()
-            .fold(Price::default(), |acc, &price| acc + price);
-        match strategy {
-            Strategy::BuyIndexSellUnderlying => {
-                assert!(
-                    theo_price > index_price,
-                    "Must be selling the underlying at a higher price than buying the index",
-                );
-            }
-            Strategy::BuyUnderlyingSellIndex => {
-                assert!(
-                    index_price > theo_price,
-                    "Must be selling the index at a higher price than buying the underlying",
-                );
-            }
+    if index_volume == 0 {
+        return Ok(None);
+    }
+    let theo_price = underlying_price
+        .iter()
+        .try_fold(Price::default(), |acc, &price| acc.checked_add(price))?;
+    let (index_side, underlying_side) = match strategy {
+        Strategy::BuyIndexSellUnderlying => {
+            assert!(
+                theo_price > index_price,
+                "Must be selling the underlying at a higher price than buying the index",
+            );
+            (Side::Buy, Side::Sell)
         }
-        let volume = index_volume.min(Volume(100)); // Cap arbs volume at 100
-        orders.push(AddMessage {
-            message_type: MessageType::Add,
-            product: index
-                .last()
-                .expect("There are 4 items in buy_underlying_sell_index_iters")
-                .product
-                .clone(),
-            price: index_price,
-            side: if strategy == Strategy::BuyUnderlyingSellIndex {
-                Side::Sell
-            } else {
-                Side::Buy
-            },
-            volume,
-            order_type: OrderType::Ioc,
-        });
-        for (i, price) in underlying_price.into_iter().enumerate() {
-            let book = index.get(i).expect("Book does not exist");
-            orders.push(AddMessage {
-                message_type: MessageType::Add,
-                product: book.product.clone(),
-                price,
-                side: if strategy == Strategy::BuyUnderlyingSellIndex {
-                    Side::Buy
-                } else {
-                    Side::Sell
-                },
-                volume,
-                order_type: OrderType::Ioc,
-            });
+        Strategy::BuyUnderlyingSellIndex => {
+            assert!(
+                index_price > theo_price,
+                "Must be selling the index at a higher price than buying the underlying",
+            );
+            (Side::Sell, Side::Buy)
+        }
+    };
+    // Size down to what every leg can trade without going beyond its position limit
+    let volume = headroom[..3]
+        .iter()
+        .map(|headroom| headroom.side(underlying_side))
+        .fold(
+            index_volume
+                .min(params.max_volume)
+                .min(headroom[3].side(index_side)),
+            Volume::min,
+        );
+    if volume == 0 {
+        return Ok(None);
+    }
+    let books = [index[3], index[0], index[1], index[2]];
+    let legs = books
+        .iter()
+        .zip([
+            index_side,
+            underlying_side,
+            underlying_side,
+            underlying_side,
+        ])
+        .map(|(book, side)| Leg::new(book, side, volume))
+        .collect::<Result<_, _>>()?;
+    match Arb::new(&books, legs, params) {
+        Ok(arb) => Ok(Some(arb)),
+        Err(err) => {
+            warn!(expiry = %index[0].expiry, "Arb rejected, {err}");
+            Ok(None)
         }
     }
-    orders
 }
 
-pub fn find_arbs(index: &[&Book; 4], credit: Price) -> Vec<AddMessage> {
-    let mut orders = find_arbs_for_side(index, Strategy::BuyUnderlyingSellIndex, credit);
-    if orders.is_empty() {
-        orders = find_arbs_for_side(index, Strategy::BuyIndexSellUnderlying, credit);
+/// Arbs sized to the headroom of each book in the index, fails when a book holds less volume at a price than we have resting there
+pub fn find_arbs(
+    index: &[&Book; 4],
+    headroom: &[Headroom; 4],
+    params: &IndexParams,
+) -> Result<Option<Arb>, TypeError> {
+    match find_arbs_for_side(index, headroom, Strategy::BuyUnderlyingSellIndex, params)? {
+        Some(arb) => Ok(Some(arb)),
+        None => find_arbs_for_side(index, headroom, Strategy::BuyIndexSellUnderlying, params),
+    }
+}
+
+/// Trades the index against its underlying, working on its own copy of the books
+pub struct IndexArbitrage {
+    pub name: String,
+    pub config: ArbitrageConfig,
+    /// Reloads the config when its file changes
+    pub watcher: Option<ConfigWatcher>,
+    pub book_states: Arc<Mutex<BookStates>>,
+    /// How often indices with outstanding arbs are retried when no feed events arrive
+    pub interval: Duration,
+    /// How long a book found inconsistent stays disabled before it is reported again
+    pub inconsistent: Duration,
+    pub books: HashMap<String, Arc<Book>>,
+    /// Version of each book the last time its index was evaluated without finding any arbs
+    pub evaluated: HashMap<String, u64>,
+}
+
+impl strategy::Strategy for IndexArbitrage {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_book(&mut self, book: Arc<Book>) -> Vec<OrderIntent> {
+        self.books.insert(book.product.clone(), book);
+        self.find_orders()
+    }
+
+    fn on_halt(&mut self, product: &str) {
+        self.books.remove(product);
+        self.evaluated.remove(product);
+    }
+
+    fn on_timer(&mut self) -> Vec<OrderIntent> {
+        self.reload();
+        self.find_orders()
+    }
+
+    fn timer_interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+impl IndexArbitrage {
+    pub fn new(book_states: Arc<Mutex<BookStates>>, watchdog: &WatchdogConfig) -> Self {
+        IndexArbitrage {
+            name: String::from("index_arbitrage"),
+            config: ArbitrageConfig::default(),
+            watcher: None,
+            book_states,
+            interval: watchdog.interval,
+            inconsistent: watchdog.inconsistent,
+            books: HashMap::new(),
+            evaluated: HashMap::new(),
+        }
+    }
+
+    /// Load the config from a file that is watched for changes for the rest of the session
+    pub fn with_config_file(mut self, path: PathBuf) -> Result<Self, ConfigError> {
+        let (watcher, config) = ConfigWatcher::new(path)?;
+        self.config = config;
+        self.watcher = Some(watcher);
+        Ok(self)
+    }
+
+    /// Swap in the config if its file changed, keeping the current one if the new one is invalid
+    pub fn reload(&mut self) {
+        let Some(result) = self.watcher.as_mut().and_then(ConfigWatcher::poll) else {
+            return;
+        };
+        match result {
+            Ok(config) => {
+                if config != self.config {
+                    info!(?config, "Reloaded arbitrage config");
+                    self.config = config;
+                    // Indices without arbs under the old parameters may have some now
+                    self.evaluated.clear();
+                }
+            }
+            Err(err) => warn!(error = %err, "Keeping the previous arbitrage config"),
+        }
+    }
+
+    /// Arbs for every enabled index with a book that changed since the index last had no arbs
+    pub fn find_orders(&mut self) -> Vec<OrderIntent> {
+        let mut changed = HashSet::new();
+        for book in self.books.values() {
+            if self.evaluated.get(&book.product) != Some(&book.version) {
+                changed.insert(book.expiry.as_str());
+            }
+        }
+
+        let mut indices: HashMap<&str, [&Book; 4]> = HashMap::new();
+        for book in self
+            .books
+            .values()
+            .filter(|book| changed.contains(book.expiry.as_str()))
+        {
+            let entry = indices.entry(&book.expiry).or_insert([book; 4]);
+            entry[book.station_id as usize] = book;
+        }
+
+        let mut all_orders = Vec::new();
+        for index in indices.values() {
+            if !index_enabled!(index, self.book_states.lock().unwrap()) {
+                continue;
+            }
+            let headroom = index.map(|book| {
+                Headroom::new(&book.position, self.config.position_limit(&book.product))
+            });
+            let arb = match find_arbs(index, &headroom, &self.config.index(&index[0].expiry)) {
+                Ok(arb) => arb,
+                Err(err) => {
+                    error!(expiry = %index[0].expiry, error = %err, "Failed to evaluate index");
+                    let mut book_states = self.book_states.lock().unwrap();
+                    for book in index.iter().filter(|book| {
+                        book.others_depth(Side::Buy).is_err()
+                            || book.others_depth(Side::Sell).is_err()
+                    }) {
+                        error!(product = %book.product, "Own depth exceeds the book");
+                        book_states.disable(
+                            &book.product,
+                            DisableReason::Inconsistent,
+                            self.inconsistent,
+                        );
+                    }
+                    continue;
+                }
+            };
+            let Some(arb) = arb else {
+                for book in index {
+                    self.evaluated.insert(book.product.clone(), book.version);
+                }
+                continue;
+            };
+            info!(expiry = %index[0].expiry, "Found arb {arb}");
+            all_orders.push(arb.orders);
+        }
+        all_orders
     }
-    orders
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::book::Position;
     use std::collections::{BTreeMap, HashMap};
     static EXPIRY: &str = "2024-01-04 09:50+1100";
     static PRODUCT1: &str = "1";
@@ -183,6 +557,19 @@ mod tests {
     static PRODUCT3: &str = "3";
     static PRODUCT4: &str = "4";
 
+    fn orders(arb: Result<Option<Arb>, TypeError>) -> Vec<AddMessage> {
+        arb.expect("Books are consistent")
+            .map(|arb| arb.orders)
+            .unwrap_or_default()
+    }
+
+    fn params(credit: Price) -> IndexParams {
+        IndexParams {
+            credit,
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn test_no_orders() {
         let books = [
@@ -192,7 +579,11 @@ mod tests {
             Book::default(),
         ];
         assert_eq!(
-            find_arbs(&[&books[0], &books[1], &books[2], &books[3]], Price(0)),
+            orders(find_arbs(
+                &[&books[0], &books[1], &books[2], &books[3]],
+                &[Headroom::UNLIMITED; 4],
+                &params(Price(0))
+            )),
             vec![],
         );
     }
@@ -208,6 +599,7 @@ mod tests {
                 product: PRODUCT1.to_string(),
                 station_id: Station::SydAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([(Price(200), Volume(6)), (Price(100), Volume(5))]),
@@ -222,6 +614,7 @@ mod tests {
                 product: PRODUCT2.to_string(),
                 station_id: Station::SydOlympicPark,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([(Price(500), Volume(1)), (Price(300), Volume(8))]),
@@ -231,6 +624,7 @@ mod tests {
                 product: PRODUCT3.to_string(),
                 station_id: Station::CanberraAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([(Price(1400), Volume(9)), (Price(1350), Volume(2))]),
@@ -240,10 +634,15 @@ mod tests {
                 product: PRODUCT4.to_string(),
                 station_id: Station::Index,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
         ];
         assert_eq!(
-            find_arbs(&[&books[0], &books[1], &books[2], &books[3]], Price(0)),
+            orders(find_arbs(
+                &[&books[0], &books[1], &books[2], &books[3]],
+                &[Headroom::UNLIMITED; 4],
+                &params(Price(0))
+            )),
             vec![],
         );
     }
@@ -259,6 +658,7 @@ mod tests {
                 product: PRODUCT1.to_string(),
                 station_id: Station::SydAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::new(),
@@ -268,6 +668,7 @@ mod tests {
                 product: PRODUCT2.to_string(),
                 station_id: Station::SydOlympicPark,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::new(),
@@ -281,6 +682,7 @@ mod tests {
                 product: PRODUCT3.to_string(),
                 station_id: Station::CanberraAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([
@@ -295,10 +697,15 @@ mod tests {
                 product: PRODUCT4.to_string(),
                 station_id: Station::Index,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
         ];
         assert_eq!(
-            find_arbs(&[&books[0], &books[1], &books[2], &books[3]], Price(600)),
+            orders(find_arbs(
+                &[&books[0], &books[1], &books[2], &books[3]],
+                &[Headroom::UNLIMITED; 4],
+                &params(Price(600))
+            )),
             vec![],
         );
     }
@@ -314,6 +721,7 @@ mod tests {
                 product: PRODUCT1.to_string(),
                 station_id: Station::SydAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::new(),
@@ -323,6 +731,7 @@ mod tests {
                 product: PRODUCT2.to_string(),
                 station_id: Station::SydOlympicPark,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::new(),
@@ -336,6 +745,7 @@ mod tests {
                 product: PRODUCT3.to_string(),
                 station_id: Station::CanberraAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([
@@ -350,10 +760,15 @@ mod tests {
                 product: PRODUCT4.to_string(),
                 station_id: Station::Index,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
         ];
         assert_eq!(
-            find_arbs(&[&books[0], &books[1], &books[2], &books[3]], Price(0)),
+            orders(find_arbs(
+                &[&books[0], &books[1], &books[2], &books[3]],
+                &[Headroom::UNLIMITED; 4],
+                &params(Price(0))
+            )),
             vec![
                 AddMessage {
                     message_type: MessageType::Add,
@@ -389,6 +804,39 @@ mod tests {
                 },
             ],
         );
+
+        let arb = find_arbs(
+            &[&books[0], &books[1], &books[2], &books[3]],
+            &[Headroom::UNLIMITED; 4],
+            &IndexParams {
+                sweep: Sweep::PerLevel,
+                ..params(Price(0))
+            },
+        )
+        .expect("Books are consistent")
+        .expect("Has an arb");
+        assert_eq!(
+            arb.orders
+                .iter()
+                .map(|order| (order.product.as_str(), order.price, order.volume))
+                .collect::<Vec<_>>(),
+            vec![
+                (PRODUCT4, Price(3500), Volume(1)),
+                (PRODUCT4, Price(3400), Volume(3)),
+                (PRODUCT4, Price(3200), Volume(1)),
+                (PRODUCT1, Price(1100), Volume(4)),
+                (PRODUCT1, Price(1200), Volume(1)),
+                (PRODUCT2, Price(1300), Volume(5)),
+                (PRODUCT3, Price(500), Volume(2)),
+                (PRODUCT3, Price(600), Volume(3)),
+            ],
+        );
+        assert!(arb.legs.iter().all(|leg| leg.volume == Volume(5)));
+        assert_eq!(arb.legs[0].average_price(), 33.8);
+        assert_eq!(arb.legs[1].average_price(), 11.2);
+        assert_eq!(arb.legs[3].worst_price(), Price(600));
+        // Sell the index for 169.00 and buy the underlying for 149.00
+        assert_eq!(arb.expected_edge(), 2000);
     }
 
     #[test]
@@ -402,6 +850,7 @@ mod tests {
                 product: PRODUCT1.to_string(),
                 station_id: Station::SydAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::new(),
@@ -411,6 +860,7 @@ mod tests {
                 product: PRODUCT2.to_string(),
                 station_id: Station::SydOlympicPark,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::new(),
@@ -424,6 +874,7 @@ mod tests {
                 product: PRODUCT3.to_string(),
                 station_id: Station::CanberraAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([
@@ -438,10 +889,15 @@ mod tests {
                 product: PRODUCT4.to_string(),
                 station_id: Station::Index,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
         ];
         assert_eq!(
-            find_arbs(&[&books[0], &books[1], &books[2], &books[3]], Price(100)),
+            orders(find_arbs(
+                &[&books[0], &books[1], &books[2], &books[3]],
+                &[Headroom::UNLIMITED; 4],
+                &params(Price(100))
+            )),
             vec![
                 AddMessage {
                     message_type: MessageType::Add,
@@ -490,6 +946,7 @@ mod tests {
                 product: PRODUCT1.to_string(),
                 station_id: Station::SydAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([(Price(800), Volume(100)), (Price(950), Volume(50))]),
@@ -499,6 +956,7 @@ mod tests {
                 product: PRODUCT2.to_string(),
                 station_id: Station::SydOlympicPark,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::new(),
@@ -512,6 +970,7 @@ mod tests {
                 product: PRODUCT3.to_string(),
                 station_id: Station::CanberraAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([
@@ -526,10 +985,15 @@ mod tests {
                 product: PRODUCT4.to_string(),
                 station_id: Station::Index,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
         ];
         assert_eq!(
-            find_arbs(&[&books[0], &books[1], &books[2], &books[3]], Price(0)),
+            orders(find_arbs(
+                &[&books[0], &books[1], &books[2], &books[3]],
+                &[Headroom::UNLIMITED; 4],
+                &params(Price(0))
+            )),
             vec![
                 AddMessage {
                     message_type: MessageType::Add,
@@ -578,6 +1042,7 @@ mod tests {
                 product: PRODUCT1.to_string(),
                 station_id: Station::SydAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::new(),
@@ -587,6 +1052,7 @@ mod tests {
                 product: PRODUCT2.to_string(),
                 station_id: Station::SydOlympicPark,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::new(),
@@ -596,6 +1062,7 @@ mod tests {
                 product: PRODUCT3.to_string(),
                 station_id: Station::CanberraAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([
@@ -610,10 +1077,15 @@ mod tests {
                 product: PRODUCT4.to_string(),
                 station_id: Station::Index,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
         ];
         assert_eq!(
-            find_arbs(&[&books[0], &books[1], &books[2], &books[3]], Price(0)),
+            orders(find_arbs(
+                &[&books[0], &books[1], &books[2], &books[3]],
+                &[Headroom::UNLIMITED; 4],
+                &params(Price(0))
+            )),
             vec![
                 AddMessage {
                     message_type: MessageType::Add,
@@ -662,6 +1134,7 @@ mod tests {
                 product: PRODUCT1.to_string(),
                 station_id: Station::SydAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::new(),
@@ -671,6 +1144,7 @@ mod tests {
                 product: PRODUCT2.to_string(),
                 station_id: Station::SydOlympicPark,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::new(),
@@ -684,6 +1158,7 @@ mod tests {
                 product: PRODUCT3.to_string(),
                 station_id: Station::CanberraAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([
@@ -697,10 +1172,15 @@ mod tests {
                 product: PRODUCT4.to_string(),
                 station_id: Station::Index,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
         ];
         assert_eq!(
-            find_arbs(&[&books[0], &books[1], &books[2], &books[3]], Price(0)),
+            orders(find_arbs(
+                &[&books[0], &books[1], &books[2], &books[3]],
+                &[Headroom::UNLIMITED; 4],
+                &params(Price(0))
+            )),
             vec![
                 AddMessage {
                     message_type: MessageType::Add,
@@ -749,6 +1229,7 @@ mod tests {
                 product: PRODUCT1.to_string(),
                 station_id: Station::SydAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::new(),
@@ -758,6 +1239,7 @@ mod tests {
                 product: PRODUCT2.to_string(),
                 station_id: Station::SydOlympicPark,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::new(),
@@ -771,6 +1253,7 @@ mod tests {
                 product: PRODUCT3.to_string(),
                 station_id: Station::CanberraAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([(Price(3500), Volume(1)), (Price(3400), Volume(3))]),
@@ -780,10 +1263,15 @@ mod tests {
                 product: PRODUCT4.to_string(),
                 station_id: Station::Index,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
         ];
         assert_eq!(
-            find_arbs(&[&books[0], &books[1], &books[2], &books[3]], Price(0)),
+            orders(find_arbs(
+                &[&books[0], &books[1], &books[2], &books[3]],
+                &[Headroom::UNLIMITED; 4],
+                &params(Price(0))
+            )),
             vec![
                 AddMessage {
                     message_type: MessageType::Add,
@@ -832,6 +1320,7 @@ mod tests {
                 product: PRODUCT1.to_string(),
                 station_id: Station::SydAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([(Price(200), Volume(6)), (Price(100), Volume(5))]),
@@ -846,6 +1335,7 @@ mod tests {
                 product: PRODUCT2.to_string(),
                 station_id: Station::SydOlympicPark,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([(Price(500), Volume(1)), (Price(300), Volume(8))]),
@@ -855,6 +1345,7 @@ mod tests {
                 product: PRODUCT3.to_string(),
                 station_id: Station::CanberraAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([(Price(900), Volume(9)), (Price(350), Volume(2))]),
@@ -864,10 +1355,15 @@ mod tests {
                 product: PRODUCT4.to_string(),
                 station_id: Station::Index,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
         ];
         assert_eq!(
-            find_arbs(&[&books[0], &books[1], &books[2], &books[3]], Price(0)),
+            orders(find_arbs(
+                &[&books[0], &books[1], &books[2], &books[3]],
+                &[Headroom::UNLIMITED; 4],
+                &params(Price(0))
+            )),
             vec![
                 AddMessage {
                     message_type: MessageType::Add,
@@ -916,6 +1412,7 @@ mod tests {
                 product: PRODUCT1.to_string(),
                 station_id: Station::SydAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([(Price(200), Volume(6)), (Price(100), Volume(5))]),
@@ -930,6 +1427,7 @@ mod tests {
                 product: PRODUCT2.to_string(),
                 station_id: Station::SydOlympicPark,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([(Price(500), Volume(1)), (Price(300), Volume(8))]),
@@ -939,6 +1437,7 @@ mod tests {
                 product: PRODUCT3.to_string(),
                 station_id: Station::CanberraAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([(Price(900), Volume(9)), (Price(350), Volume(2))]),
@@ -948,10 +1447,15 @@ mod tests {
                 product: PRODUCT4.to_string(),
                 station_id: Station::Index,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
         ];
         assert_eq!(
-            find_arbs(&[&books[0], &books[1], &books[2], &books[3]], Price(0)),
+            orders(find_arbs(
+                &[&books[0], &books[1], &books[2], &books[3]],
+                &[Headroom::UNLIMITED; 4],
+                &params(Price(0))
+            )),
             vec![
                 AddMessage {
                     message_type: MessageType::Add,
@@ -1000,6 +1504,7 @@ mod tests {
                 product: PRODUCT1.to_string(),
                 station_id: Station::SydAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([(Price(200), Volume(6)), (Price(100), Volume(5))]),
@@ -1014,6 +1519,7 @@ mod tests {
                 product: PRODUCT2.to_string(),
                 station_id: Station::SydOlympicPark,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([(Price(500), Volume(1)), (Price(300), Volume(8))]),
@@ -1023,6 +1529,7 @@ mod tests {
                 product: PRODUCT3.to_string(),
                 station_id: Station::CanberraAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::new(),
@@ -1032,10 +1539,15 @@ mod tests {
                 product: PRODUCT4.to_string(),
                 station_id: Station::Index,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
         ];
         assert_eq!(
-            find_arbs(&[&books[0], &books[1], &books[2], &books[3]], Price(0)),
+            orders(find_arbs(
+                &[&books[0], &books[1], &books[2], &books[3]],
+                &[Headroom::UNLIMITED; 4],
+                &params(Price(0))
+            )),
             vec![],
         );
     }
@@ -1051,6 +1563,7 @@ mod tests {
                 product: PRODUCT1.to_string(),
                 station_id: Station::SydAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([(Price(200), Volume(6)), (Price(100), Volume(5))]),
@@ -1065,6 +1578,7 @@ mod tests {
                 product: PRODUCT2.to_string(),
                 station_id: Station::SydOlympicPark,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::from([(Price(500), Volume(1)), (Price(300), Volume(8))]),
@@ -1074,6 +1588,7 @@ mod tests {
                 product: PRODUCT3.to_string(),
                 station_id: Station::CanberraAirport,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
             Book {
                 bids: BTreeMap::new(),
@@ -1083,11 +1598,251 @@ mod tests {
                 product: PRODUCT4.to_string(),
                 station_id: Station::Index,
                 expiry: EXPIRY.to_string(),
+                ..Default::default()
             },
         ];
         assert_eq!(
-            find_arbs(&[&books[0], &books[1], &books[2], &books[3]], Price(0)),
+            orders(find_arbs(
+                &[&books[0], &books[1], &books[2], &books[3]],
+                &[Headroom::UNLIMITED; 4],
+                &params(Price(0))
+            )),
             vec![],
         );
     }
+
+    #[test]
+    fn test_config() {
+        use crate::strategy::Strategy as _;
+
+        let book = |product: &str, station_id, bids: Vec<_>, asks: Vec<_>| {
+            Arc::new(Book {
+                bids: BTreeMap::from_iter(bids),
+                asks: BTreeMap::from_iter(asks),
+                product: product.to_string(),
+                station_id,
+                expiry: EXPIRY.to_string(),
+                ..Default::default()
+            })
+        };
+        let mut strategy = IndexArbitrage::new(
+            Arc::new(Mutex::new(BookStates::default())),
+            &WatchdogConfig::default(),
+        );
+        strategy.config.indices.insert(
+            EXPIRY.to_string(),
+            crate::config::IndexOverride {
+                credit: Some(Price(0)),
+                max_volume: Some(Volume(2)),
+                sweep: None,
+                off_grid: None,
+            },
+        );
+        for book in [
+            book(
+                PRODUCT1,
+                Station::SydAirport,
+                vec![],
+                vec![(Price(1100), Volume(10))],
+            ),
+            book(
+                PRODUCT2,
+                Station::SydOlympicPark,
+                vec![],
+                vec![(Price(1300), Volume(10))],
+            ),
+            book(
+                PRODUCT3,
+                Station::CanberraAirport,
+                vec![],
+                vec![(Price(500), Volume(10))],
+            ),
+        ] {
+            assert_eq!(strategy.on_book(book), Vec::<Vec<_>>::new());
+        }
+        let orders = strategy.on_book(book(
+            PRODUCT4,
+            Station::Index,
+            vec![(Price(3500), Volume(10))],
+            vec![],
+        ));
+        assert_eq!(orders.len(), 1);
+        assert!(orders[0].iter().all(|order| order.volume == Volume(2)));
+
+        // Short 1 lot with a limit of 2 leaves room to sell only 1 more
+        let mut index = (*strategy.books[PRODUCT4]).clone();
+        index.position.position = -1;
+        index.version += 1;
+        strategy
+            .books
+            .insert(PRODUCT4.to_string(), Arc::new(index.clone()));
+        strategy
+            .config
+            .position_limits
+            .insert(PRODUCT4.to_string(), 2);
+        let orders = strategy.find_orders();
+        assert_eq!(orders.len(), 1);
+        assert!(orders[0].iter().all(|order| order.volume == Volume(1)));
+
+        // Our resting ask could fill and use up the rest of the headroom
+        index.position.ask_exposure = Volume(1);
+        index.version += 1;
+        strategy
+            .books
+            .insert(PRODUCT4.to_string(), Arc::new(index.clone()));
+        assert_eq!(strategy.find_orders(), Vec::<Vec<_>>::new());
+        assert!(strategy.book_states.lock().unwrap().is_enabled(PRODUCT4));
+
+        // More of our own volume than the level holds disables the book rather than panicking
+        index.own_bids.insert(Price(3500), Volume(20));
+        index.version += 1;
+        strategy.books.insert(PRODUCT4.to_string(), Arc::new(index));
+        assert_eq!(strategy.find_orders(), Vec::<Vec<_>>::new());
+        let book_states = strategy.book_states.lock().unwrap();
+        assert!(!book_states.is_enabled(PRODUCT4));
+        assert!(book_states.is_enabled(PRODUCT1));
+    }
+
+    #[test]
+    fn test_own_quote_is_not_liquidity() {
+        use crate::{feed::AddedMessage, username::Username};
+
+        let mut books = [
+            (PRODUCT1, Station::SydAirport),
+            (PRODUCT2, Station::SydOlympicPark),
+            (PRODUCT3, Station::CanberraAirport),
+            (PRODUCT4, Station::Index),
+        ]
+        .map(|(product, station_id)| {
+            Book::new(product.to_string(), station_id, EXPIRY.to_string())
+        });
+        let mut id = 0;
+        let mut add = |book: &mut Book, side, price, owner| {
+            id += 1;
+            book.add_order(
+                AddedMessage {
+                    product: book.product.clone(),
+                    id: id.to_string(),
+                    side,
+                    price,
+                    filled: Volume(0),
+                    resting: Volume(5),
+                    owner,
+                    sequence: id,
+                },
+                &Username::KLiang,
+            )
+            .expect("Message applies to the book");
+        };
+        for book in books[..3].iter_mut() {
+            add(book, Side::Sell, Price(1000), Username::PRao);
+        }
+        add(&mut books[3], Side::Buy, Price(3000), Username::PRao);
+        // Our own bid is the only one above the theo
+        add(&mut books[3], Side::Buy, Price(3600), Username::KLiang);
+        assert_eq!(books[3].bbo().0.expect("Has bids").price, Price(3600));
+        assert_eq!(
+            books[3]
+                .others_depth(Side::Buy)
+                .expect("Own depth fits the book")
+                .collect::<Vec<_>>(),
+            vec![PriceLevel {
+                price: Price(3000),
+                volume: Volume(5),
+            }],
+        );
+        assert_eq!(
+            find_arbs(
+                &[&books[0], &books[1], &books[2], &books[3]],
+                &[Headroom::UNLIMITED; 4],
+                &params(Price(0)),
+            ),
+            Ok(None),
+        );
+
+        // Someone else joining our bid is an arb again
+        add(&mut books[3], Side::Buy, Price(3600), Username::PRao);
+        let arb = find_arbs(
+            &[&books[0], &books[1], &books[2], &books[3]],
+            &[Headroom::UNLIMITED; 4],
+            &params(Price(0)),
+        )
+        .expect("Books are consistent")
+        .expect("Has an arb");
+        assert_eq!(
+            arb.legs[0].levels,
+            vec![PriceLevel {
+                price: Price(3600),
+                volume: Volume(5),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_off_grid() {
+        let book = |product: &str, station_id, bids: Vec<_>, asks: Vec<_>| Book {
+            bids: BTreeMap::from_iter(bids),
+            asks: BTreeMap::from_iter(asks),
+            product: product.to_string(),
+            station_id,
+            expiry: EXPIRY.to_string(),
+            ..Default::default()
+        };
+        let mut books = [
+            book(
+                PRODUCT1,
+                Station::SydAirport,
+                vec![],
+                vec![(Price(1100), Volume(10))],
+            ),
+            book(
+                PRODUCT2,
+                Station::SydOlympicPark,
+                vec![],
+                vec![(Price(1300), Volume(10))],
+            ),
+            book(
+                PRODUCT3,
+                Station::CanberraAirport,
+                vec![],
+                vec![(Price(505), Volume(10))],
+            ),
+            book(
+                PRODUCT4,
+                Station::Index,
+                vec![(Price(3500), Volume(10))],
+                vec![],
+            ),
+        ];
+        books[2].tick_size = Some(Price(10));
+        let index = [&books[0], &books[1], &books[2], &books[3]];
+        assert_eq!(
+            find_arbs(&index, &[Headroom::UNLIMITED; 4], &params(Price(0))),
+            Ok(None),
+        );
+
+        let arb = find_arbs(
+            &index,
+            &[Headroom::UNLIMITED; 4],
+            &IndexParams {
+                off_grid: OffGrid::Round,
+                ..params(Price(0))
+            },
+        )
+        .expect("Books are consistent")
+        .expect("Has an arb");
+        // Buying rounds up so the order still reaches the level
+        assert_eq!(
+            arb.orders
+                .iter()
+                .map(|order| (order.product.as_str(), order.price))
+                .collect::<Vec<_>>(),
+            vec![
+                (PRODUCT4, Price(3500)),
+                (PRODUCT1, Price(1100)),
+                (PRODUCT2, Price(1300)),
+                (PRODUCT3, Price(510)),
+            ],
+        );
+    }
 }
@@ -1,88 +1,104 @@
+//! The trader: recovers the books, follows the feed and runs the strategies against it.
+
 use crate::{
-    arbitrage::find_arbs,
     book::Book,
+    counterparty::Counterparties,
+    execution::{Execution, ExecutionConfig, ExecutionEvent},
     feed::{HasSequence, Message},
-    order::OrderAddedMessage,
+    invariants::InvariantChecker,
+    latency::OrderLatency,
+    markout::Markouts,
+    metrics::{Exporter, Metrics},
+    observations::{poll_observations, Station},
+    reconcile::{self, ReconcileConfig, ReconcileEvent, Reconciler},
+    strategy::{self, Attribution, BookSlot, Strategy, StrategyEvent, StrategyRegistry},
+    tape::Tape,
     types::Price,
     username::Username,
+    watchdog::{BookStates, DisableReason, WatchdogConfig},
 };
 use futures_util::stream::{SplitStream, StreamExt};
-use serde_json::{from_slice, from_str, to_string};
+use serde_json::from_slice;
 use std::{
+    cmp::Ordering,
     collections::HashMap,
+    io::Write,
+    net::SocketAddr,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::TcpStream,
+    spawn,
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+    task::{spawn_blocking, JoinHandle},
+    time::{interval, MissedTickBehavior},
 };
-use tokio::{net::TcpStream, spawn};
 use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 
+/// Address of an exchange endpoint, naming the port by its `ConstantPorts` constant such as `url!(EXECUTION_PORT, "execution")`
 #[macro_export]
 macro_rules! url {
-    ($port:expr, $endpoint:expr) => {
-        format!("http://{}:{}/{}", AutoTrader::HOSTNAME, $port, $endpoint)
+    ($port:ident, $endpoint:expr) => {
+        $crate::url!("http", $port, $endpoint)
     };
-    ($protocol:expr, $port:expr, $endpoint:expr) => {
+    ($protocol:expr, $port:ident, $endpoint:expr) => {
         format!(
             "{}://{}:{}/{}",
             $protocol,
-            AutoTrader::HOSTNAME,
-            $port,
+            <$crate::autotrader::AutoTrader as $crate::autotrader::ConstantPorts>::HOSTNAME,
+            <$crate::autotrader::AutoTrader as $crate::autotrader::ConstantPorts>::$port,
             $endpoint
         )
     };
 }
 
-macro_rules! send_order {
-    ($username:expr, $password:expr, $message:expr) => {
-        reqwest::Client::new()
-            .post(url!(AutoTrader::EXECUTION_PORT, "execution"))
-            .form(&[
-                ("username", $username),
-                ("password", &$password),
-                (
-                    "message",
-                    &to_string(&$message).expect("Failed to serializase AddMessage"),
-                ),
-            ])
-            .send()
-            .await
-    };
-}
-
-macro_rules! index_enabled {
-    ($index:ident, $enabled_books:ident, $orders_to_wait:ident) => {
-        $index.iter().all(|book| {
-            *$enabled_books
-                .lock()
-                .unwrap()
-                .get(&book.product)
-                .expect("Book does not exist")
-                && $orders_to_wait.lock().unwrap().get(&book.product).is_none()
-        })
-    };
-    ($index:ident, $enabled_books:ident) => {
-        $index.iter().all(|book| {
-            *$enabled_books
-                .lock()
-                .unwrap()
-                .get(&book.product)
-                .expect("Book does not exist")
-        })
-    };
-}
-
+/// The book a message applies to, copied first if a strategy still holds a snapshot of it
 macro_rules! get_book {
     ($books:expr, $message:ident) => {
-        $books
-            .get_mut(&$message.product)
-            .expect("Book does not exist")
+        Arc::make_mut(
+            $books
+                .get_mut(&$message.product)
+                .expect("Book does not exist"),
+        )
     };
 }
 
 pub struct AutoTrader {
     pub username: Username,
     pub password: String,
-    pub books: HashMap<String, Book>,
+    /// Shared with the snapshots published to the strategies, so a book is only copied when it changes while one is held
+    pub books: HashMap<String, Arc<Book>>,
     pub sequence: u32,
+    /// Long lived execution client so orders reuse kept alive connections
+    pub client: reqwest::Client,
+    pub execution: ExecutionConfig,
+    pub latency: Arc<Mutex<OrderLatency>>,
+    /// Why each book is currently not allowed to trade
+    pub book_states: Arc<Mutex<BookStates>>,
+    pub watchdog: WatchdogConfig,
+    pub strategies: StrategyRegistry,
+    /// Fills and PnL of each strategy
+    pub attribution: Arc<Mutex<Attribution>>,
+    /// Every trade seen on the feed, shared with the strategies
+    pub tape: Arc<Mutex<Tape>>,
+    /// Whether the messages being applied are replayed from `/recover` rather than received live
+    pub recovering: bool,
+    /// What every other trader on the feed has been doing
+    pub counterparties: Arc<Mutex<Counterparties>>,
+    /// How the market moved after each of our fills
+    pub markouts: Markouts,
+    /// Periodically check the books against a fresh `/recover` snapshot when set
+    pub reconcile: Option<ReconcileConfig>,
+    /// Checks every book after each message applied to it when set
+    pub invariants: Option<InvariantChecker>,
+    /// Tick size of the products of each station, inferred from the feed for the others
+    pub tick_sizes: HashMap<Station, Price>,
+    /// Published by the feed and execution tasks for the metrics endpoint
+    pub metrics: Arc<Mutex<Metrics>>,
+    /// Serve the metrics in the Prometheus text format on this address when set
+    pub metrics_addr: Option<SocketAddr>,
 }
 
 pub trait ConstantPorts {
@@ -99,46 +115,238 @@ impl ConstantPorts for AutoTrader {
     const HOSTNAME: &'static str = "sytev070";
 }
 
-impl AutoTrader {
-    const POSITION_LIMIT: i16 = 1000;
-}
-
 impl AutoTrader {
     pub fn new(username: Username, password: String) -> AutoTrader {
+        let execution = ExecutionConfig::default();
+        let counterparties = Counterparties::new(username.clone());
+        let markouts = Markouts::new(username.clone(), Markouts::HORIZONS.to_vec());
         AutoTrader {
             username,
             password,
             books: HashMap::new(),
             sequence: 0,
+            client: execution.client(),
+            execution,
+            latency: Arc::new(Mutex::new(OrderLatency::default())),
+            book_states: Arc::new(Mutex::new(BookStates::default())),
+            watchdog: WatchdogConfig::default(),
+            strategies: StrategyRegistry::default(),
+            attribution: Arc::new(Mutex::new(Attribution::default())),
+            tape: Arc::new(Mutex::new(Tape::default())),
+            recovering: false,
+            counterparties: Arc::new(Mutex::new(counterparties)),
+            markouts,
+            reconcile: None,
+            invariants: None,
+            tick_sizes: HashMap::new(),
+            metrics: Arc::new(Mutex::new(Metrics::default())),
+            metrics_addr: None,
         }
     }
 
-    pub async fn startup(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let (stream, response) =
-            connect_async(url!("ws", AutoTrader::FEED_RECOVERY_PORT, "information"))
-                .await
-                .expect("Failed to connect to the websocket");
-        println!("Server responded with headers: {:?}", response.headers());
+    /// Add a strategy to run side by side with the others once the trader starts up
+    pub fn register(&mut self, strategy: Box<dyn Strategy>) {
+        self.strategies.register(strategy);
+    }
+
+    pub fn with_execution_config(mut self, execution: ExecutionConfig) -> AutoTrader {
+        self.client = execution.client();
+        self.execution = execution;
+        self
+    }
+
+    /// Mark out our fills at these horizons after each fill instead of the defaults
+    pub fn with_markout_horizons(mut self, horizons: Vec<Duration>) -> AutoTrader {
+        self.markouts = Markouts::new(self.username.clone(), horizons);
+        self
+    }
+
+    pub fn with_reconcile_config(mut self, reconcile: ReconcileConfig) -> AutoTrader {
+        self.reconcile = Some(reconcile);
+        self
+    }
+
+    pub fn with_invariant_checks(mut self) -> AutoTrader {
+        self.invariants = Some(InvariantChecker::new(self.username.clone()));
+        self
+    }
+
+    pub fn with_tick_size(mut self, station: Station, tick_size: Price) -> AutoTrader {
+        assert!(tick_size.0 > 0, "Tick size must be positive");
+        self.tick_sizes.insert(station, tick_size);
+        self
+    }
+
+    pub fn with_metrics_endpoint(mut self, addr: SocketAddr) -> AutoTrader {
+        self.metrics_addr = Some(addr);
+        self
+    }
 
-        let messages: Vec<Message> = reqwest::get(url!(AutoTrader::FEED_RECOVERY_PORT, "recover"))
+    /// Export every print on the feed to the writer as JSON lines
+    pub fn with_tape_export(self, writer: impl Write + Send + 'static) -> AutoTrader {
+        self.tape.lock().unwrap().export_to(writer);
+        self
+    }
+
+    /// Rebuilds the books from `/recover`, subscribe to the feed first so no message is missed in between
+    pub async fn recover(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let messages: Vec<Message> = reqwest::get(url!(FEED_RECOVERY_PORT, "recover"))
             .await?
             .json()
             .await?;
+        self.recovering = true;
         for message in messages {
             self.sequence = message.sequence();
             self.parse_feed_message(message);
         }
-        println!("Finished recovery books: {:#?}", self.books.keys());
+        self.recovering = false;
+        info!(
+            sequence = self.sequence,
+            books = ?self.books.keys().collect::<Vec<_>>(),
+            "Finished recovery",
+        );
+        Ok(())
+    }
+
+    /// Whether a feed message is the next one to apply, false for one that was already applied and an error after a gap
+    pub fn is_next(&self, message: &Message) -> Result<bool, String> {
+        let next_sequence = self.sequence + 1;
+        match message.sequence().cmp(&next_sequence) {
+            Ordering::Less => Ok(false),
+            Ordering::Equal => Ok(true),
+            Ordering::Greater => Err(format!(
+                "Expecting sequence number {} but got {}",
+                next_sequence,
+                message.sequence(),
+            )),
+        }
+    }
 
-        self.poll(stream.split().1).await?;
+    /// Applies a live feed message to the books without trading, returning whether it was new
+    pub fn apply(&mut self, message: Message) -> Result<bool, String> {
+        if !self.is_next(&message)? {
+            return Ok(false);
+        }
+        self.sequence = message.sequence();
+        self.parse_feed_message(message);
+        Ok(true)
+    }
+
+    pub async fn startup(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let (stream, response) = connect_async(url!("ws", FEED_RECOVERY_PORT, "information"))
+            .await
+            .expect("Failed to connect to the websocket");
+        debug!(headers = ?response.headers(), "Connected to the feed");
+
+        self.recover().await?;
+
+        let metrics_task = self.metrics_addr.map(|addr| {
+            let exporter = Exporter {
+                metrics: self.metrics.clone(),
+                book_states: self.book_states.clone(),
+                attribution: self.attribution.clone(),
+                latency: self.latency.clone(),
+            };
+            spawn(async move {
+                if let Err(err) = exporter.serve(addr).await {
+                    error!(%addr, error = %err, "Metrics endpoint failed");
+                }
+            })
+        });
+
+        let (execution, execution_events) = unbounded_channel();
+        let mut strategies = HashMap::new();
+        let mut book_slots = Vec::new();
+        let mut strategy_tasks = Vec::new();
+        let snapshots: Vec<_> = self.books.values().cloned().collect();
+        for strategy in self.strategies.take() {
+            let (events, receiver) = unbounded_channel();
+            let books = Arc::new(BookSlot::default());
+            for book in &snapshots {
+                books.publish(book.clone());
+            }
+            strategies.insert(strategy.name().to_string(), events);
+            book_slots.push(books.clone());
+            let span = info_span!("strategy", strategy = strategy.name());
+            strategy_tasks.push(spawn(
+                strategy::run(strategy, receiver, books, execution.clone()).instrument(span),
+            ));
+        }
+        let execution_task = spawn(Execution::new(self, strategies.clone()).run(execution_events));
+        let strategies: Vec<_> = strategies.into_values().collect();
+        let observation_task = spawn(poll_observations(strategies.clone()));
+
+        self.poll(stream.split().1, &strategies, &book_slots, &execution)
+            .await?;
+        observation_task.abort();
+        if let Some(metrics_task) = metrics_task {
+            metrics_task.abort();
+        }
+        drop(strategies);
+        drop(execution);
+        for strategy_task in strategy_tasks {
+            strategy_task.await?;
+        }
+        execution_task.await?;
+        info!("Order latencies:\n{}", self.latency.lock().unwrap());
+        if let Some((sequence, message, violations)) = self
+            .invariants
+            .as_ref()
+            .and_then(|invariants| invariants.broken.as_ref())
+        {
+            error!(sequence, message, ?violations, "Books first broke");
+        }
+        info!(
+            "Disabled books at the end of the session:\n{}",
+            self.book_states.lock().unwrap(),
+        );
+        info!("Strategy PnL:\n{}", self.attribution.lock().unwrap());
+        info!(
+            "Markouts:\n{}",
+            self.markouts.report(&self.attribution.lock().unwrap()),
+        );
+        let mut books: Vec<_> = self.books.values().collect();
+        books.sort_by_key(|book| &book.product);
+        for book in books {
+            info!(product = %book.product, "{}", book.metrics(5));
+            if book.fees != 0 {
+                info!(
+                    product = %book.product,
+                    "Paid {:.2} in broker fees",
+                    book.fees as f64 / 100.0,
+                );
+            }
+        }
+        let mut tape = self.tape.lock().unwrap();
+        for (product, stats) in tape.session_stats() {
+            info!(product, "{stats}");
+        }
+        for (product, stats) in tape.session_broker_stats() {
+            info!(product, "Broker trades {stats}");
+        }
+        info!(
+            "Counterparties:\n{}",
+            self.counterparties.lock().unwrap().report(
+                self.books.values().map(Arc::as_ref),
+                &self.markouts.by_counterparty(),
+            ),
+        );
+        tape.flush()?;
         Ok(())
     }
 
     /// Outbound decoder for feed
     fn parse_feed_message(&mut self, message: Message) {
-        #[cfg(debug_assertions)]
-        dbg!(&message);
-        match message {
+        let now = Instant::now();
+        let sequence = message.sequence();
+        let message_type = message.message_type();
+        let product = message.product().map(str::to_string);
+        let _span = info_span!("feed", sequence, product = product.as_deref()).entered();
+        trace!(?message, "Applying feed message");
+        // Fills due by now are marked at the books as they stood until this message
+        self.markouts.update(now, &self.books);
+        let described = self.invariants.as_ref().map(|_| format!("{message:?}"));
+        let applied = match message {
             Message::Future(future) => {
                 assert_eq!(
                     future.expiry, future.halt_time,
@@ -146,161 +354,318 @@ impl AutoTrader {
                 );
                 self.books.insert(
                     future.product.clone(),
-                    Book::new(future.product, future.station_id, future.expiry),
+                    Arc::new(Book {
+                        broker_fee: future.broker_fee,
+                        tick_size: self.tick_sizes.get(&future.station_id).copied(),
+                        ..Book::new(future.product, future.station_id, future.expiry)
+                    }),
                 );
+                Ok(())
             }
             Message::Added(added) => {
-                get_book!(self.books, added).add_order(added, &self.username);
+                self.counterparties.lock().unwrap().added(&added);
+                get_book!(self.books, added).add_order(added, &self.username)
             }
             Message::Deleted(deleted) => {
-                get_book!(self.books, deleted).remove_order(deleted, &self.username);
+                get_book!(self.books, deleted).remove_order(deleted, &self.username)
             }
             Message::Trade(trade) => {
-                get_book!(self.books, trade).trade(trade, &self.username);
+                let received = (!self.recovering).then_some(now);
+                self.tape.lock().unwrap().record(&trade, received);
+                self.counterparties.lock().unwrap().trade(&trade);
+                if !self.recovering {
+                    self.markouts.trade(&trade, now);
+                }
+                get_book!(self.books, trade).trade(trade, &self.username)
             }
             Message::Settlement(settlement) => {
-                println!(
-                    "Book {} settles at {:?}",
-                    settlement.product, settlement.price,
+                info!(
+                    product = %settlement.product,
+                    price = ?settlement.price,
+                    "Book settles",
                 );
+                self.markouts.settle(&settlement.product, settlement.price);
+                Ok(())
             }
             Message::Index(index) => {
-                println!("Index definition: {index:#?}");
+                info!(?index, "Index definition");
+                Ok(())
             }
             Message::TradingHalt(halt) => {
                 self.books.remove(&halt.product);
+                Ok(())
             }
+        };
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.message(sequence, message_type, now);
+        if let Some(product) = product.as_deref() {
+            metrics.book(product, self.books.get(product).map(Arc::as_ref));
+        }
+        drop(metrics);
+        let Some(book) = product.and_then(|product| self.books.get(&product)) else {
+            return;
+        };
+        let mut book_states = self.book_states.lock().unwrap();
+        if let Err(err) = applied {
+            // The book was left as it was before the message, so it no longer matches the exchange
+            error!(error = %err, "Failed to apply feed message");
+            book_states.disable(
+                &book.product,
+                DisableReason::Inconsistent,
+                self.watchdog.inconsistent,
+            );
+        }
+        match book.cross {
+            Some(cross) => {
+                if book_states.is_enabled(&book.product) {
+                    warn!(?cross, bbo = ?book.bbo(), "Book is no longer tradable");
+                }
+                book_states.disable(&book.product, DisableReason::Crossed, self.watchdog.crossed);
+            }
+            None => book_states.enable(&book.product, DisableReason::Crossed),
+        }
+        drop(book_states);
+        if let (Some(invariants), Some(described)) = (self.invariants.as_mut(), described) {
+            invariants.check(sequence, || described, book);
         }
     }
 
-    pub async fn poll(
-        &mut self,
-        mut stream: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let enabled_books = Arc::new(Mutex::new(HashMap::new()));
-        let orders_to_wait: Arc<Mutex<HashMap<String, String>>> =
-            Arc::new(Mutex::new(HashMap::new()));
-        while let Some(message) = stream.next().await {
-            let message: Message = from_slice(&message?.into_data())?;
-            let next_sequence = self.sequence + 1;
-            #[allow(clippy::comparison_chain)]
-            if message.sequence() == next_sequence {
-                self.sequence = next_sequence;
-                if let Message::Trade(ref trade) = message {
-                    let mut orders_to_wait = orders_to_wait.lock().unwrap();
-                    if let Some(order_id) = orders_to_wait.get(&trade.product) {
-                        if trade.aggressor_order == order_id.clone() {
-                            orders_to_wait.remove(&trade.product);
-                        }
+    /// Replays a recovered snapshot and the feed messages applied since it was requested into a fresh trader at the given sequence
+    fn shadow(
+        username: Username,
+        tick_sizes: HashMap<Station, Price>,
+        snapshot: Vec<Message>,
+        buffered: &[Vec<u8>],
+        sequence: u32,
+    ) -> Result<AutoTrader, String> {
+        let mut shadow = AutoTrader::new(username, String::new());
+        shadow.tick_sizes = tick_sizes;
+        shadow.recovering = true;
+        for message in snapshot
+            .into_iter()
+            .take_while(|message| message.sequence() <= sequence)
+        {
+            shadow.sequence = message.sequence();
+            shadow.parse_feed_message(message);
+        }
+        shadow.catch_up(buffered, sequence)?;
+        Ok(shadow)
+    }
+
+    /// Applies the raw feed messages that follow our sequence, which must then be the given one
+    fn catch_up(&mut self, buffered: &[Vec<u8>], sequence: u32) -> Result<(), String> {
+        for message in buffered {
+            let message: Message = from_slice(message).map_err(|err| err.to_string())?;
+            if message.sequence() == self.sequence + 1 {
+                self.sequence = message.sequence();
+                self.parse_feed_message(message);
+            }
+        }
+        if self.sequence != sequence {
+            return Err(format!(
+                "Recovered up to sequence {} but the live books are at {}",
+                self.sequence, sequence,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Builds the shadow of a snapshot off the feed task, as of our current sequence
+    fn spawn_shadow(
+        &self,
+        snapshot: Result<Vec<Message>, String>,
+        buffered: Vec<Vec<u8>>,
+    ) -> JoinHandle<Result<AutoTrader, String>> {
+        let username = self.username.clone();
+        let tick_sizes = self.tick_sizes.clone();
+        let sequence = self.sequence;
+        spawn_blocking(move || {
+            AutoTrader::shadow(username, tick_sizes, snapshot?, &buffered, sequence)
+        })
+    }
+
+    /// Replaces the live books that differ from the recovered ones, returning their products
+    fn swap_books(&mut self, mut recovered: HashMap<String, Arc<Book>>) -> Vec<String> {
+        let mut products: Vec<_> = self.books.keys().chain(recovered.keys()).cloned().collect();
+        products.sort();
+        products.dedup();
+        products.retain(|product| self.books.get(product) != recovered.get(product));
+        for product in products.iter() {
+            match recovered.remove(product) {
+                Some(mut book) => {
+                    // Strategies skip books whose version they have already evaluated
+                    if let Some(live) = self.books.get(product) {
+                        let book = Arc::make_mut(&mut book);
+                        book.version = book.version.max(live.version + 1);
                     }
+                    self.books.insert(product.clone(), book);
+                }
+                None => {
+                    self.books.remove(product);
                 }
-                self.parse_feed_message(message);
-            } else if message.sequence() > next_sequence {
-                panic!(
-                    "Expecting sequence number {} but got {}",
-                    next_sequence,
-                    message.sequence(),
-                );
             }
+            self.metrics
+                .lock()
+                .unwrap()
+                .book(product, self.books.get(product).map(Arc::as_ref));
+            self.book_states
+                .lock()
+                .unwrap()
+                .enable(product, DisableReason::Inconsistent);
+        }
+        products
+    }
 
-            let mut indices: HashMap<String, [&Book; 4]> = HashMap::new();
-            for book in self.books.values() {
-                let position = book.position.position;
-                let mut enabled_books = enabled_books.lock().unwrap();
-                if !enabled_books.contains_key(&book.product) {
-                    enabled_books.insert(
-                        book.product.clone(),
-                        if position > 0 {
-                            position <= AutoTrader::POSITION_LIMIT
-                        } else {
-                            position >= -AutoTrader::POSITION_LIMIT
-                        },
-                    );
+    /// Compares the live books with a shadow built from a recovered snapshot, returning the products of the books swapped in
+    fn reconcile(
+        &mut self,
+        shadow: Result<AutoTrader, String>,
+        buffered: &[Vec<u8>],
+        swap: bool,
+    ) -> Vec<String> {
+        // Bring the shadow up to the messages applied while it was being built
+        let shadow = shadow.and_then(|mut shadow| {
+            shadow.catch_up(buffered, self.sequence)?;
+            Ok(shadow)
+        });
+        let shadow = match shadow {
+            Ok(shadow) => shadow,
+            Err(err) => {
+                warn!(sequence = self.sequence, error = %err, "Failed to reconcile books");
+                return Vec::new();
+            }
+        };
+        let diffs = reconcile::diff(&self.books, &shadow.books);
+        if diffs.is_empty() {
+            return Vec::new();
+        }
+        warn!(
+            sequence = self.sequence,
+            "Books diverged from /recover:\n{}",
+            diffs.join("\n"),
+        );
+        if !swap {
+            return Vec::new();
+        }
+        let products = self.swap_books(shadow.books);
+        warn!(
+            sequence = self.sequence,
+            ?products,
+            "Swapped in recovered books"
+        );
+        products
+    }
+
+    /// Publishes one snapshot of a changed book shared by every strategy, or tells them it stopped trading
+    fn publish_book(
+        &self,
+        product: String,
+        strategies: &[UnboundedSender<StrategyEvent>],
+        book_slots: &[Arc<BookSlot>],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.books.get(&product) {
+            Some(book) => {
+                for slot in book_slots {
+                    slot.publish(book.clone());
                 }
-                let entry = indices.entry(book.expiry.clone()).or_insert([&book; 4]);
-                entry[book.station_id as usize] = book;
             }
-            for index in indices.values() {
-                if !index_enabled!(index, enabled_books, orders_to_wait) {
+            None => {
+                for slot in book_slots {
+                    slot.remove(&product);
+                }
+                for strategy in strategies {
+                    strategy.send(StrategyEvent::Halt(product.clone()))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Feed task applying every message to the books and publishing what changed to the strategies and execution
+    pub async fn poll(
+        &mut self,
+        mut stream: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+        strategies: &[UnboundedSender<StrategyEvent>],
+        book_slots: &[Arc<BookSlot>],
+        execution: &UnboundedSender<ExecutionEvent>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut reconciler = self.reconcile.clone().map(Reconciler::new);
+        let mut shadow_task = None;
+        // Marks fills that come due while the feed is quiet
+        let mut markout_timer = interval(self.watchdog.interval);
+        markout_timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            let data = tokio::select! {
+                _ = markout_timer.tick() => {
+                    self.markouts.update(Instant::now(), &self.books);
                     continue;
                 }
-                let orders = find_arbs(index, Price(500));
-                for order in orders.iter() {
-                    let position = self
-                        .books
-                        .get(&order.product)
-                        .expect("Book does not exist")
-                        .position
-                        .position;
-                    if position > 0 && position + order.volume > AutoTrader::POSITION_LIMIT
-                        || position < 0 && position - order.volume < -AutoTrader::POSITION_LIMIT
-                    {
-                        // Disable the books that are about to go over position limit
-                        if let Some(enable) = enabled_books.lock().unwrap().get_mut(&order.product)
-                        {
-                            *enable = false;
+                message = stream.next() => match message {
+                    Some(message) => message?.into_data(),
+                    None => break,
+                },
+                event = reconcile::next_event(&mut reconciler) => {
+                    let reconciler = reconciler
+                        .as_mut()
+                        .expect("Events only come from a reconciler");
+                    match event {
+                        ReconcileEvent::Fetch => {
+                            reconciler.fetch(url!(FEED_RECOVERY_PORT, "recover"));
+                        }
+                        ReconcileEvent::Snapshot(snapshot) => {
+                            // Keep buffering the feed for the shadow to catch up on once built
+                            let buffered = reconciler.drain_buffered();
+                            shadow_task = Some(self.spawn_shadow(snapshot, buffered));
                         }
                     }
+                    continue;
                 }
-                if index_enabled!(index, enabled_books) {
-                    for order in orders {
-                        // Disable the books where an order is about to be sent
-                        enabled_books
-                            .lock()
-                            .unwrap()
-                            .entry(order.product.clone())
-                            .and_modify(|enabled| *enabled = false);
-                        let username = self.username.clone();
-                        let password = self.password.clone();
-                        let orders_to_wait = orders_to_wait.clone();
-                        let enabled_books = enabled_books.clone();
-                        spawn(async move {
-                            let result = send_order!(
-                                to_string(&username)
-                                    .expect("Failed to convert username to string")
-                                    .trim_matches('"'),
-                                password,
-                                order
-                            );
-                            match result {
-                                Ok(response) => {
-                                    let response = response
-                                        .text()
-                                        .await
-                                        .expect("Failed to parse add order response");
-                                    match from_str::<OrderAddedMessage>(&response) {
-                                        Ok(json) => {
-                                            assert_eq!(
-                                                json.resting, 0,
-                                                "For IOC orders there should be no resting volume",
-                                            );
-                                            if json.filled > 0 {
-                                                // Insert the book ID associated with the order ID if an order has been filled
-                                                orders_to_wait
-                                                    .lock()
-                                                    .unwrap()
-                                                    .insert(order.product.clone(), json.id.clone());
-                                            }
-                                            #[cfg(debug_assertions)]
-                                            dbg!(json);
-                                        }
-                                        Err(err) => {
-                                            dbg!(response, err);
-                                        }
-                                    }
-                                }
-                                Err(err) => {
-                                    dbg!(err);
-                                }
-                            }
-                            // Reenable book after receiving a response
-                            enabled_books
-                                .lock()
-                                .unwrap()
-                                .entry(order.product.clone())
-                                .and_modify(|enabled| *enabled = true);
-                        });
+                shadow = next_shadow(&mut shadow_task) => {
+                    shadow_task = None;
+                    let reconciler = reconciler
+                        .as_mut()
+                        .expect("Shadows are only built for a reconciler");
+                    let buffered = reconciler.take_buffered();
+                    let swapped = self.reconcile(shadow, &buffered, reconciler.config.swap);
+                    for product in swapped {
+                        self.publish_book(product, strategies, book_slots)?;
                     }
+                    continue;
+                }
+            };
+            let message: Message = from_slice(&data)?;
+            match self.is_next(&message) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(err) => panic!("{err}"),
+            }
+            self.sequence = message.sequence();
+            if let Some(reconciler) = reconciler.as_mut() {
+                reconciler.record(&data);
+            }
+            let product = message.product().map(str::to_string);
+            let mut trade_event = None;
+            match &message {
+                Message::Added(added) if added.owner == self.username => {
+                    execution.send(ExecutionEvent::Added(added.id.clone()))?;
+                }
+                Message::Trade(trade) => {
+                    let trade = Arc::new(trade.clone());
+                    execution.send(ExecutionEvent::Trade(trade.clone()))?;
+                    trade_event = Some(StrategyEvent::Trade(trade));
+                }
+                Message::TradingHalt(halt) => {
+                    execution.send(ExecutionEvent::Halt(halt.product.clone()))?;
+                }
+                _ => {}
+            }
+            self.parse_feed_message(message);
+            if let Some(product) = product {
+                self.publish_book(product, strategies, book_slots)?;
+            }
+            if let Some(event) = trade_event {
+                for strategy in strategies {
+                    strategy.send(event.clone())?;
                 }
             }
         }
@@ -308,10 +673,21 @@ impl AutoTrader {
     }
 }
 
+/// The next shadow built off the feed task, if one is being built at all
+async fn next_shadow(
+    task: &mut Option<JoinHandle<Result<AutoTrader, String>>>,
+) -> Result<AutoTrader, String> {
+    match task {
+        Some(task) => task.await.map_err(|err| err.to_string())?,
+        None => std::future::pending().await,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
+        arbitrage::IndexArbitrage,
         book::{Order, Position, PriceLevel},
         observations::Station,
         types::{Price, Volume},
@@ -357,7 +733,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::new(),
                     asks: BTreeMap::new(),
                     orders: HashMap::new(),
@@ -369,7 +745,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -391,7 +768,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::new(),
                     asks: BTreeMap::new(),
                     orders: HashMap::new(),
@@ -403,7 +780,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -425,7 +803,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::new(),
                     asks: BTreeMap::new(),
                     orders: HashMap::new(),
@@ -437,7 +815,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -459,7 +838,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::new(),
                     asks: BTreeMap::new(),
                     orders: HashMap::new(),
@@ -471,7 +850,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -491,7 +871,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::new(),
                     asks: BTreeMap::from([(Price(1716), Volume(75))]),
                     orders: HashMap::from([(
@@ -510,7 +890,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -532,7 +913,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::new(),
                     asks: BTreeMap::from([(Price(1716), Volume(65))]),
                     orders: HashMap::from([(
@@ -551,7 +932,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -573,7 +955,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::new(),
                     asks: BTreeMap::from([(Price(1716), Volume(65))]),
                     orders: HashMap::from([(
@@ -592,7 +974,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -614,7 +997,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::new(),
                     asks: BTreeMap::from([(Price(1716), Volume(55))]),
                     orders: HashMap::from([(
@@ -633,7 +1016,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
     }
@@ -673,7 +1057,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::new(),
                     asks: BTreeMap::new(),
                     orders: HashMap::new(),
@@ -685,7 +1069,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -719,7 +1104,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::from([(Price(2431), Volume(20))]),
                     asks: BTreeMap::new(),
                     orders: HashMap::from([(
@@ -738,7 +1123,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -799,7 +1185,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::from([(Price(2431), Volume(35))]),
                     asks: BTreeMap::from([(Price(3329), Volume(20)), (Price(3101), Volume(50))]),
                     orders: HashMap::from([
@@ -844,7 +1230,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -877,7 +1264,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::from([(Price(2431), Volume(15))]),
                     asks: BTreeMap::from([(Price(3329), Volume(20)), (Price(3101), Volume(50))]),
                     orders: HashMap::from([
@@ -914,7 +1301,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -975,7 +1363,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::from([
                         (Price(2431), Volume(15)),
                         (Price(2890), Volume(5)),
@@ -1044,7 +1432,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -1083,7 +1472,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::from([
                         (Price(2431), Volume(15)),
                         (Price(2890), Volume(5)),
@@ -1140,7 +1529,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -1195,7 +1585,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::from([(Price(2431), Volume(15)), (Price(2890), Volume(3))]),
                     asks: BTreeMap::from([(Price(3329), Volume(20)), (Price(3101), Volume(50))]),
                     orders: HashMap::from([
@@ -1240,7 +1630,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -1278,7 +1669,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::from([(Price(2431), Volume(15)), (Price(2890), Volume(3))]),
                     asks: BTreeMap::from([(Price(3329), Volume(20)), (Price(3101), Volume(52))]),
                     orders: HashMap::from([
@@ -1331,7 +1722,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -1370,7 +1762,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::from([(Price(2431), Volume(15)), (Price(2890), Volume(3))]),
                     asks: BTreeMap::from([(Price(3329), Volume(20)), (Price(3101), Volume(51))]),
                     orders: HashMap::from([
@@ -1423,7 +1815,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -1462,7 +1855,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::from([(Price(2431), Volume(15)), (Price(2890), Volume(3))]),
                     asks: BTreeMap::from([(Price(3329), Volume(20)), (Price(3101), Volume(50))]),
                     orders: HashMap::from([
@@ -1507,7 +1900,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -1546,7 +1940,7 @@ mod tests {
             trader.books,
             HashMap::from([(
                 PRODUCT.to_string(),
-                Book {
+                Arc::new(Book {
                     bids: BTreeMap::from([(Price(2431), Volume(15)), (Price(2890), Volume(3))]),
                     asks: BTreeMap::from([(Price(3329), Volume(20)), (Price(3101), Volume(48))]),
                     orders: HashMap::from([
@@ -1591,7 +1985,8 @@ mod tests {
                     product: PRODUCT.to_string(),
                     station_id: Station::SydOlympicPark,
                     expiry: EXPIRY.to_string(),
-                },
+                    ..Default::default()
+                }),
             )]),
         );
 
@@ -1612,4 +2007,516 @@ mod tests {
 
         assert_eq!(trader.books, HashMap::new());
     }
+
+    #[test]
+    fn test_broker_trade() {
+        let mut trader = AutoTrader::new(Username::KLiang, String::new());
+        parse_json!(trader, {
+            "type": "FUTURE",
+            "product": PRODUCT,
+            "stationId": 66212,
+            "stationName": "SYDNEY OLYMPIC PARK AWS (ARCHERY CENTRE)",
+            "expiry": EXPIRY,
+            "haltTime": EXPIRY,
+            "brokerFee": 0.05,
+            "sequence": 1,
+        });
+        parse_json!(trader, {
+            "type": "TRADE",
+            "product": PRODUCT,
+            "price": 20.00,
+            "volume": 4,
+            "buyer": "prao",
+            "seller": "kliang",
+            "tradeType": "BROKER_TRADE",
+            "passiveOrder": "",
+            "passiveOrderRemaining": 0,
+            "aggressorOrder": "",
+            "sequence": 2
+        });
+
+        let book = trader.books.get(PRODUCT).expect("Book does not exist");
+        assert_eq!(book.position.position, -4);
+        assert_eq!(book.fees, 20);
+        let tape = trader.tape.lock().unwrap();
+        assert!(tape.prints(PRODUCT).is_empty());
+        assert_eq!(tape.broker_prints(PRODUCT).len(), 1);
+    }
+
+    #[test]
+    fn test_publish_book_copy_on_write() {
+        let mut trader = AutoTrader::new(Username::KLiang, String::new());
+        let slots = [Arc::new(BookSlot::default())];
+        let added = |id, sequence| {
+            from_value(json!({
+                "type": "ADDED",
+                "product": PRODUCT,
+                "id": id,
+                "side": "BUY",
+                "price": 20.00,
+                "filled": 0,
+                "resting": 10,
+                "owner": "prao",
+                "sequence": sequence,
+            }))
+            .expect("Failed to parse feed message")
+        };
+        parse_json!(trader, {
+            "type": "FUTURE",
+            "product": PRODUCT,
+            "stationId": 66212,
+            "stationName": "SYDNEY OLYMPIC PARK AWS (ARCHERY CENTRE)",
+            "expiry": EXPIRY,
+            "haltTime": EXPIRY,
+            "sequence": 1,
+        });
+        trader
+            .publish_book(PRODUCT.to_string(), &[], &slots)
+            .expect("Book is published");
+        let snapshot = slots[0].take().pop().expect("Book was published");
+        assert!(Arc::ptr_eq(&snapshot, &trader.books[PRODUCT]));
+
+        // The held snapshot is left as it was
+        trader.parse_feed_message(added("1", 2));
+        assert!(snapshot.bids.is_empty());
+        assert_eq!(trader.books[PRODUCT].bids.len(), 1);
+
+        // Nothing holds the book anymore so it is changed in place
+        drop(snapshot);
+        let book = Arc::as_ptr(&trader.books[PRODUCT]);
+        trader.parse_feed_message(added("2", 3));
+        assert_eq!(Arc::as_ptr(&trader.books[PRODUCT]), book);
+    }
+
+    #[test]
+    fn test_apply_in_sequence() {
+        let mut trader = AutoTrader::new(Username::KLiang, String::new());
+        let future = |sequence| {
+            from_value(json!({
+                "type": "FUTURE",
+                "product": PRODUCT,
+                "stationId": 66212,
+                "stationName": "SYDNEY OLYMPIC PARK AWS (ARCHERY CENTRE)",
+                "expiry": EXPIRY,
+                "haltTime": EXPIRY,
+                "sequence": sequence,
+            }))
+            .expect("Failed to parse feed message")
+        };
+        assert_eq!(trader.apply(future(1)), Ok(true));
+        assert!(trader.books.contains_key(PRODUCT));
+        assert_eq!(trader.apply(future(1)), Ok(false));
+        assert!(trader.apply(future(3)).is_err());
+        assert_eq!(trader.sequence, 1);
+    }
+
+    #[test]
+    fn test_overflowing_message() {
+        let mut trader = AutoTrader::new(Username::KLiang, String::new());
+        let added = |id, resting, sequence| {
+            json!({
+                "type": "ADDED",
+                "product": PRODUCT,
+                "id": id,
+                "side": "BUY",
+                "price": 20.00,
+                "filled": 0,
+                "resting": resting,
+                "owner": "prao",
+                "sequence": sequence,
+            })
+        };
+        parse_json!(trader, {
+            "type": "FUTURE",
+            "product": PRODUCT,
+            "stationId": 66212,
+            "stationName": "SYDNEY OLYMPIC PARK AWS (ARCHERY CENTRE)",
+            "expiry": EXPIRY,
+            "haltTime": EXPIRY,
+            "sequence": 1,
+        });
+        trader.parse_feed_message(
+            from_value(added("1", u32::MAX, 2)).expect("Failed to parse feed message"),
+        );
+        let before = trader.books.get(PRODUCT).cloned();
+        trader.parse_feed_message(
+            from_value(added("2", 1, 3)).expect("Failed to parse feed message"),
+        );
+        assert_eq!(trader.books.get(PRODUCT), before.as_ref());
+        assert_eq!(
+            trader
+                .book_states
+                .lock()
+                .unwrap()
+                .disabled_books()
+                .into_iter()
+                .map(|(product, reason, _)| (product.to_string(), reason))
+                .collect::<Vec<_>>(),
+            vec![(PRODUCT.to_string(), DisableReason::Inconsistent)]
+        );
+    }
+
+    #[test]
+    fn test_recovered_fills() {
+        let mut trader = AutoTrader::new(Username::KLiang, String::new());
+        let trade = |sequence| {
+            json!({
+                "type": "TRADE",
+                "product": PRODUCT,
+                "price": 20.00,
+                "volume": 4,
+                "buyer": "kliang",
+                "seller": "prao",
+                "tradeType": "BUY_AGGRESSOR",
+                "passiveOrder": "1",
+                "passiveOrderRemaining": 0,
+                "aggressorOrder": "2",
+                "sequence": sequence,
+            })
+        };
+        trader.recovering = true;
+        parse_json!(trader, {
+            "type": "FUTURE",
+            "product": PRODUCT,
+            "stationId": 66212,
+            "stationName": "SYDNEY OLYMPIC PARK AWS (ARCHERY CENTRE)",
+            "expiry": EXPIRY,
+            "haltTime": EXPIRY,
+            "sequence": 1,
+        });
+        trader.parse_feed_message(from_value(trade(2)).expect("Failed to parse feed message"));
+        assert!(trader.markouts.fills.is_empty());
+        assert!(trader.tape.lock().unwrap().prints(PRODUCT).is_empty());
+
+        trader.recovering = false;
+        trader.parse_feed_message(from_value(trade(3)).expect("Failed to parse feed message"));
+        assert_eq!(trader.markouts.fills.len(), 1);
+        let tape = trader.tape.lock().unwrap();
+        assert_eq!(tape.prints(PRODUCT).len(), 1);
+        assert_eq!(tape.session_stats()[0].1.volume, 8);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let future = json!({
+            "type": "FUTURE",
+            "product": PRODUCT,
+            "stationId": 66212,
+            "stationName": "SYDNEY OLYMPIC PARK AWS (ARCHERY CENTRE)",
+            "expiry": EXPIRY,
+            "haltTime": EXPIRY,
+            "sequence": 1,
+        });
+        let added = |id: &str, sequence| {
+            json!({
+                "type": "ADDED",
+                "product": PRODUCT,
+                "id": id,
+                "side": "BUY",
+                "price": 10.00,
+                "filled": 0,
+                "resting": 10,
+                "owner": "kliang",
+                "sequence": sequence,
+            })
+        };
+        let mut trader = AutoTrader::new(Username::KLiang, String::new());
+        for message in [future.clone(), added("1", 2), added("2", 3)] {
+            let message: Message = from_value(message).expect("Failed to parse feed message");
+            trader.sequence = message.sequence();
+            trader.parse_feed_message(message);
+        }
+        // The snapshot was taken at sequence 2 and the third message arrived while it was in flight
+        let snapshot = || {
+            vec![
+                from_value(future.clone()).expect("Failed to parse feed message"),
+                from_value(added("1", 2)).expect("Failed to parse feed message"),
+            ]
+        };
+        let buffered = [added("2", 3).to_string().into_bytes()];
+        let shadow = |buffered: &[Vec<u8>], sequence| {
+            AutoTrader::shadow(
+                Username::KLiang,
+                HashMap::new(),
+                snapshot(),
+                buffered,
+                sequence,
+            )
+        };
+        let caught_up = shadow(&buffered, 3).expect("Snapshot catches up");
+        assert_eq!(caught_up.books, trader.books);
+        assert!(shadow(&[], 3).is_err());
+
+        // Lose our second order's volume from the live book
+        let book = Arc::make_mut(trader.books.get_mut(PRODUCT).expect("Book does not exist"));
+        book.bids.insert(Price(1000), Volume(10));
+        book.position.bid_exposure = Volume(10);
+        let version = book.version;
+        // The shadow was built at sequence 2 and catches up on the feed task
+        let built = || shadow(&[], 2);
+        assert_eq!(
+            trader.reconcile(built(), &buffered, false),
+            Vec::<String>::new()
+        );
+        assert_ne!(trader.books, caught_up.books);
+        assert_eq!(
+            trader.reconcile(built(), &buffered, true),
+            vec![PRODUCT.to_string()],
+        );
+        assert_eq!(trader.books, caught_up.books);
+        assert!(
+            trader
+                .books
+                .get(PRODUCT)
+                .expect("Book does not exist")
+                .version
+                > version
+        );
+    }
+
+    /// Futures for two indices followed by a stream of orders that never cross into an arb
+    fn synthetic_session(messages: usize) -> Vec<serde_json::Value> {
+        let stations = [66037, 66212, 70351, 1];
+        let expiries = ["2024-01-04 09:50+1100", "2024-01-04 10:00+1100"];
+        let mut session = Vec::new();
+        for expiry in expiries {
+            for station in stations {
+                session.push(json!({
+                    "type": "FUTURE",
+                    "product": format!("{expiry} {station}"),
+                    "stationId": station,
+                    "stationName": "",
+                    "expiry": expiry,
+                    "haltTime": expiry,
+                    "sequence": session.len() + 1,
+                }));
+            }
+        }
+        let mut resting: Vec<Vec<(String, &str)>> = vec![Vec::new(); 8];
+        let mut seed: u64 = 1;
+        for id in 0..messages {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let book = (seed >> 33) as usize % 8;
+            let product = format!("{} {}", expiries[book / 4], stations[book % 4]);
+            let sequence = session.len() + 1;
+            if resting[book].len() > 20 {
+                let (id, side) = resting[book].swap_remove((seed >> 40) as usize % 20);
+                session.push(json!({
+                    "type": "DELETED",
+                    "product": product,
+                    "id": id,
+                    "side": side,
+                    "sequence": sequence,
+                }));
+                continue;
+            }
+            let side = if seed >> 63 == 0 { "BUY" } else { "SELL" };
+            let tick = ((seed >> 20) % 900) as f64 / 100.0;
+            // Underlying bids sum up to less than the index asks and the other way around
+            let price = match (book % 4 == 3, side) {
+                (false, "BUY") => 10.0 + tick,
+                (false, _) => 21.0 + tick,
+                (true, "BUY") => 30.0 + tick * 3.0,
+                (true, _) => 61.0 + tick * 3.0,
+            };
+            session.push(json!({
+                "type": "ADDED",
+                "product": product,
+                "id": id.to_string(),
+                "side": side,
+                "price": price,
+                "filled": 0,
+                "resting": 10,
+                "owner": "cchuah",
+                "sequence": sequence,
+            }));
+            resting[book].push((id.to_string(), side));
+        }
+        session
+    }
+
+    #[test]
+    fn test_crossed_book_is_disabled() {
+        let mut trader = AutoTrader::new(Username::KLiang, String::new());
+        parse_json!(trader, {
+            "type": "FUTURE",
+            "product": PRODUCT,
+            "stationId": 66212,
+            "stationName": "SYDNEY OLYMPIC PARK AWS (ARCHERY CENTRE)",
+            "expiry": EXPIRY,
+            "haltTime": EXPIRY,
+            "sequence": 1,
+        });
+        let added = |id: &str, side: &str, sequence| {
+            from_value(json!({
+                "type": "ADDED",
+                "product": PRODUCT,
+                "id": id,
+                "side": side,
+                "price": 10.00,
+                "filled": 0,
+                "resting": 10,
+                "owner": "cchuah",
+                "sequence": sequence,
+            }))
+            .expect("Failed to parse feed message")
+        };
+        trader.parse_feed_message(added("1", "SELL", 2));
+        trader.parse_feed_message(added("2", "BUY", 3));
+        assert!(!trader.book_states.lock().unwrap().is_enabled(PRODUCT));
+
+        parse_json!(trader, {
+            "type": "DELETED",
+            "product": PRODUCT,
+            "id": "2",
+            "side": "BUY",
+            "sequence": 4
+        });
+        assert!(trader.book_states.lock().unwrap().is_enabled(PRODUCT));
+        assert_eq!(
+            trader
+                .books
+                .get(PRODUCT)
+                .expect("Book does not exist")
+                .locked_count,
+            1,
+        );
+    }
+
+    #[test]
+    fn test_invariants() {
+        let mut trader = AutoTrader::new(Username::KLiang, String::new()).with_invariant_checks();
+        for message in synthetic_session(1000) {
+            trader.parse_feed_message(from_value(message).expect("Failed to parse feed message"));
+        }
+        let invariants = trader.invariants.as_ref().expect("Checking invariants");
+        assert_eq!(invariants.broken, None);
+
+        let book = Arc::make_mut(
+            trader
+                .books
+                .get_mut("2024-01-04 09:50+1100 1")
+                .expect("Book does not exist"),
+        );
+        *book.bids.values_mut().next().expect("Book has bids") += Volume(1);
+        parse_json!(trader, {
+            "type": "ADDED",
+            "product": "2024-01-04 09:50+1100 1",
+            "id": "new",
+            "side": "SELL",
+            "price": 99.00,
+            "filled": 0,
+            "resting": 10,
+            "owner": "cchuah",
+            "sequence": 5000
+        });
+        let (sequence, message, _) = trader
+            .invariants
+            .as_ref()
+            .and_then(|invariants| invariants.broken.clone())
+            .expect("Invariant broke");
+        assert_eq!(sequence, 5000);
+        assert!(message.contains("new"), "{message}");
+    }
+
+    /// Applies a message and publishes the book it changed to the strategy like the feed task does
+    fn publish(trader: &mut AutoTrader, strategy: &mut IndexArbitrage, message: Message) {
+        let product = match &message {
+            Message::Future(future) => future.product.clone(),
+            Message::Added(added) => added.product.clone(),
+            Message::Deleted(deleted) => deleted.product.clone(),
+            _ => unreachable!("Synthetic sessions only contain futures and orders"),
+        };
+        trader.parse_feed_message(message);
+        let book = trader.books.get(&product).expect("Book does not exist");
+        strategy.books.insert(product, book.clone());
+    }
+
+    /// Time spent applying every message and evaluating the strategy after each one
+    fn replay(session: &[serde_json::Value], incremental: bool) -> std::time::Duration {
+        let mut trader = AutoTrader::new(Username::KLiang, String::new());
+        let mut strategy = IndexArbitrage::new(trader.book_states.clone(), &trader.watchdog);
+        let messages: Vec<Message> = session
+            .iter()
+            .map(|message| from_value(message.clone()).expect("Failed to parse feed message"))
+            .collect();
+        let start = std::time::Instant::now();
+        for message in messages {
+            publish(&mut trader, &mut strategy, message);
+            if !incremental {
+                strategy.evaluated.clear();
+            }
+            strategy.find_orders();
+        }
+        start.elapsed()
+    }
+
+    #[test]
+    fn test_incremental_evaluation() {
+        let session = synthetic_session(100);
+        let mut trader = AutoTrader::new(Username::KLiang, String::new());
+        let mut strategy = IndexArbitrage::new(trader.book_states.clone(), &trader.watchdog);
+        for message in session {
+            publish(
+                &mut trader,
+                &mut strategy,
+                from_value(message).expect("Failed to parse feed message"),
+            );
+        }
+        assert_eq!(strategy.find_orders(), Vec::<Vec<_>>::new());
+        assert_eq!(strategy.evaluated.len(), 8);
+        assert!(trader
+            .books
+            .values()
+            .all(|book| strategy.evaluated.get(&book.product) == Some(&book.version)));
+
+        let product = String::from("2024-01-04 09:50+1100 1");
+        let version = trader
+            .books
+            .get(&product)
+            .expect("Book does not exist")
+            .version;
+        publish(
+            &mut trader,
+            &mut strategy,
+            from_value(json!({
+                "type": "ADDED",
+                "product": product,
+                "id": "new",
+                "side": "BUY",
+                "price": 30.00,
+                "filled": 0,
+                "resting": 10,
+                "owner": "cchuah",
+                "sequence": 1000
+            }))
+            .expect("Failed to parse feed message"),
+        );
+        assert_eq!(strategy.evaluated.get(&product), Some(&version));
+        assert_eq!(strategy.find_orders(), Vec::<Vec<_>>::new());
+        assert_eq!(strategy.evaluated.get(&product), Some(&(version + 1)));
+    }
+
+    /// Compares full and incremental evaluation over a session recorded from `/recover`, given by the `BOMEX_SESSION` environment variable, or a synthetic one
+    ///
+    /// `cargo test --release -- --ignored --nocapture bench_incremental_evaluation`
+    #[test]
+    #[ignore]
+    fn bench_incremental_evaluation() {
+        let session: Vec<serde_json::Value> = match std::env::var("BOMEX_SESSION") {
+            Ok(path) => from_slice(&std::fs::read(path).expect("Failed to read recorded session"))
+                .expect("Failed to parse recorded session"),
+            Err(_) => synthetic_session(100_000),
+        };
+        let full = replay(&session, false);
+        let incremental = replay(&session, true);
+        println!(
+            "{} messages, full: {:?} per message, incremental: {:?} per message",
+            session.len(),
+            full / session.len() as u32,
+            incremental / session.len() as u32,
+        );
+    }
 }
@@ -1,12 +1,49 @@
+//! Order books rebuilt from the feed, along with the signals derived from them.
+
 use crate::{
     feed::{AddedMessage, DeletedMessage, TradeMessage, TradeType},
     observations::Station,
-    types::{Price, Side, Volume},
+    types::{Price, Side, TypeError, Volume},
     username::Username,
 };
-use std::collections::{BTreeMap, HashMap};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Display,
+};
+
+/// A feed message that cannot be applied to a book
+#[derive(Debug, Clone, PartialEq)]
+pub enum BookError {
+    /// A volume, our position or our fees would go out of range
+    Overflow(TypeError),
+    /// The message names an order the book does not hold
+    UnknownOrder(String),
+    /// The passive order of a trade rests at another price or with other volume than the trade says
+    PassiveOrderMismatch(String),
+}
+
+impl Display for BookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BookError::Overflow(err) => write!(f, "{err}"),
+            BookError::UnknownOrder(id) => write!(f, "order {id:?} is not in the book"),
+            BookError::PassiveOrderMismatch(id) => {
+                write!(f, "passive order {id:?} differs from the trade")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BookError {}
 
-#[derive(Default, Debug, PartialEq)]
+impl From<TypeError> for BookError {
+    fn from(err: TypeError) -> Self {
+        BookError::Overflow(err)
+    }
+}
+
+/// Every order resting on a product and our position in it
+#[derive(Default, Debug, Clone)]
 pub struct Book {
     pub bids: BTreeMap<Price, Volume>,
     pub asks: BTreeMap<Price, Volume>,
@@ -15,9 +52,55 @@ pub struct Book {
     pub product: String,
     pub station_id: Station,
     pub expiry: String,
+    /// Fee per lot we pay on each side of a broker trade we are part of
+    pub broker_fee: Price,
+    /// Broker fees paid in hundredths of a dollar
+    pub fees: i64,
+    /// Tick size the product was configured with
+    pub tick_size: Option<Price>,
+    /// Largest tick every price seen on the feed is a multiple of
+    pub inferred_tick: Option<Price>,
+    /// Distinct prices seen on the feed, up to `Book::MIN_TICK_PRICES`
+    pub tick_prices: Vec<Price>,
+    /// Our own resting volume at each price, so depth can be viewed without it
+    pub own_bids: BTreeMap<Price, Volume>,
+    pub own_asks: BTreeMap<Price, Volume>,
+    /// Order IDs resting at each price in the order they arrived
+    pub bid_queues: BTreeMap<Price, VecDeque<String>>,
+    pub ask_queues: BTreeMap<Price, VecDeque<String>>,
+    /// Incremented every time the book is modified so strategies can skip books that have not changed
+    pub version: u64,
+    /// Whether the best bid is at or above the best ask after the last update
+    pub cross: Option<Cross>,
+    /// Number of times the book became locked or crossed
+    pub locked_count: u32,
+    pub crossed_count: u32,
+}
+
+/// Books are equal when their contents are, regardless of how many updates it took to get there, own depth, queues and ticks are derived from the orders
+impl PartialEq for Book {
+    fn eq(&self, other: &Self) -> bool {
+        self.bids == other.bids
+            && self.asks == other.asks
+            && self.orders == other.orders
+            && self.position == other.position
+            && self.product == other.product
+            && self.station_id == other.station_id
+            && self.expiry == other.expiry
+            && self.broker_fee == other.broker_fee
+            && self.fees == other.fees
+    }
+}
+
+/// A book whose best bid is equal to (locked) or above (crossed) its best ask
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cross {
+    Locked,
+    Crossed,
 }
 
-#[derive(Debug, PartialEq)]
+/// An order resting on the book
+#[derive(Debug, Clone, PartialEq)]
 pub struct Order {
     pub owner: Username,
     pub price: Price,
@@ -34,12 +117,33 @@ impl From<AddedMessage> for Order {
     }
 }
 
-#[derive(Default, Debug, PartialEq)]
+/// Our traded position and resting volume on each side
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct Position {
     pub bid_exposure: Volume,
     pub ask_exposure: Volume,
     /// Current traded position in the book, positive for long negative for short
-    pub position: i16,
+    pub position: i32,
+}
+
+macro_rules! get_queues {
+    ($self:ident, $side:expr) => {
+        if $side == Side::Buy {
+            &mut $self.bid_queues
+        } else {
+            &mut $self.ask_queues
+        }
+    };
+}
+
+macro_rules! get_own_side {
+    ($self:ident, $side:expr) => {
+        if $side == Side::Buy {
+            &mut $self.own_bids
+        } else {
+            &mut $self.own_asks
+        }
+    };
 }
 
 macro_rules! get_side_and_exposure {
@@ -52,6 +156,44 @@ macro_rules! get_side_and_exposure {
     };
 }
 
+/// Signals derived from the book at a point in time
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookMetrics {
+    pub mid: Option<f64>,
+    pub microprice: Option<f64>,
+    pub top_imbalance: Option<f64>,
+    /// Imbalance across the best `levels` levels of each side
+    pub imbalance: Option<f64>,
+    pub levels: usize,
+    pub spread_ticks: Option<i32>,
+    pub cross: Option<Cross>,
+    pub locked_count: u32,
+    pub crossed_count: u32,
+}
+
+impl Display for BookMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let format =
+            |value: Option<f64>| value.map_or(String::from("-"), |value| format!("{value:.3}"));
+        write!(
+            f,
+            "mid {} micro {} imbalance top {} {} levels {} spread {} {}, locked {} crossed {} times",
+            format(self.mid),
+            format(self.microprice),
+            format(self.top_imbalance),
+            self.levels,
+            format(self.imbalance),
+            self.spread_ticks
+                .map_or(String::from("-"), |spread| format!("{spread} ticks")),
+            self.cross
+                .map_or(String::from("uncrossed"), |cross| format!("{cross:?}")),
+            self.locked_count,
+            self.crossed_count,
+        )
+    }
+}
+
+/// Total volume at a price
 #[derive(Default, Clone, Copy, Debug, PartialEq)]
 pub struct PriceLevel {
     pub price: Price,
@@ -65,6 +207,11 @@ impl From<(&Price, &Volume)> for PriceLevel {
 }
 
 impl Book {
+    /// Smallest price increment when the book has neither a configured nor an inferred tick size
+    pub const DEFAULT_TICK_SIZE: Price = Price(1);
+    /// Distinct prices that must be seen before the inferred tick size is trusted, fewer could share a coarser divisor by chance
+    pub const MIN_TICK_PRICES: usize = 5;
+
     pub fn new(product: String, station_id: Station, expiry: String) -> Self {
         Book {
             bids: BTreeMap::new(),
@@ -78,10 +225,22 @@ impl Book {
             product,
             station_id,
             expiry,
+            broker_fee: Price(0),
+            fees: 0,
+            tick_size: None,
+            inferred_tick: None,
+            tick_prices: Vec::new(),
+            own_bids: BTreeMap::new(),
+            own_asks: BTreeMap::new(),
+            bid_queues: BTreeMap::new(),
+            ask_queues: BTreeMap::new(),
+            version: 0,
+            cross: None,
+            locked_count: 0,
+            crossed_count: 0,
         }
     }
 
-    #[allow(dead_code)]
     pub fn bbo(&self) -> (Option<PriceLevel>, Option<PriceLevel>) {
         (
             self.bids.last_key_value().map(|best_bid| best_bid.into()),
@@ -89,80 +248,706 @@ impl Book {
         )
     }
 
-    pub fn add_order(&mut self, added: AddedMessage, username: &Username) {
+    /// Midpoint of the best bid and ask in dollars
+    pub fn mid(&self) -> Option<f64> {
+        let (Some(bid), Some(ask)) = self.bbo() else {
+            return None;
+        };
+        Some((bid.price.0 as f64 + ask.price.0 as f64) / 200.0)
+    }
+
+    /// Best bid and ask weighted by the volume on the opposite side, in dollars
+    pub fn microprice(&self) -> Option<f64> {
+        let (Some(bid), Some(ask)) = self.bbo() else {
+            return None;
+        };
+        let (bid_volume, ask_volume) = (bid.volume.0 as f64, ask.volume.0 as f64);
+        Some(
+            (bid.price.0 as f64 * ask_volume + ask.price.0 as f64 * bid_volume)
+                / (bid_volume + ask_volume)
+                / 100.0,
+        )
+    }
+
+    /// Bid minus ask volume over their sum across the best levels of each side, from -1 to 1
+    pub fn imbalance(&self, levels: usize) -> Option<f64> {
+        let bid_volume: u64 = self
+            .bids
+            .values()
+            .rev()
+            .take(levels)
+            .map(|v| v.0 as u64)
+            .sum();
+        let ask_volume: u64 = self.asks.values().take(levels).map(|v| v.0 as u64).sum();
+        (bid_volume + ask_volume != 0)
+            .then(|| (bid_volume as f64 - ask_volume as f64) / (bid_volume + ask_volume) as f64)
+    }
+
+    /// Best ask minus best bid in ticks, negative when the book is crossed
+    pub fn spread_ticks(&self) -> Option<i32> {
+        let (Some(bid), Some(ask)) = self.bbo() else {
+            return None;
+        };
+        Some(((ask.price.0 as i64 - bid.price.0 as i64) / self.tick().0 as i64) as i32)
+    }
+
+    /// The configured tick size, falling back to the one inferred from the feed once enough distinct prices have been seen
+    pub fn tick(&self) -> Price {
+        let inferred = self
+            .inferred_tick
+            .filter(|_| self.tick_prices.len() >= Book::MIN_TICK_PRICES);
+        self.tick_size
+            .or(inferred)
+            .unwrap_or(Book::DEFAULT_TICK_SIZE)
+    }
+
+    /// Whether a price is a multiple of the tick size
+    pub fn on_grid(&self, price: Price) -> bool {
+        price.0.rem_euclid(self.tick().0) == 0
+    }
+
+    /// Rounds a price onto the grid away from the other side, lower for bids and higher for asks
+    pub fn round_passive(&self, side: Side, price: Price) -> Result<Price, TypeError> {
+        self.round(price, side == Side::Sell)
+    }
+
+    /// Rounds a price onto the grid towards the other side, higher for bids and lower for asks
+    pub fn round_aggressive(&self, side: Side, price: Price) -> Result<Price, TypeError> {
+        self.round(price, side == Side::Buy)
+    }
+
+    fn round(&self, price: Price, up: bool) -> Result<Price, TypeError> {
+        let remainder = price.0.rem_euclid(self.tick().0);
+        if remainder == 0 {
+            Ok(price)
+        } else if up {
+            price.checked_add(Price(self.tick().0 - remainder))
+        } else {
+            price.checked_sub(Price(remainder))
+        }
+    }
+
+    /// Narrows the inferred tick size to divide a price seen on the feed
+    fn infer_tick(&mut self, price: Price) {
+        if self.tick_prices.len() < Book::MIN_TICK_PRICES && !self.tick_prices.contains(&price) {
+            self.tick_prices.push(price);
+        }
+        let mut tick = self.inferred_tick.map_or(0, |tick| tick.0.unsigned_abs());
+        let mut price = price.0.unsigned_abs();
+        while price != 0 {
+            (tick, price) = (price, tick % price);
+        }
+        // Only a lone i32::MIN has a divisor beyond i32, half of it still divides it
+        self.inferred_tick = (tick != 0).then(|| Price(i32::try_from(tick).unwrap_or(1 << 30)));
+    }
+
+    /// Volume resting on a side at prices at least as good as the given one
+    pub fn depth_to(&self, side: Side, price: Price) -> Volume {
+        let levels: Box<dyn Iterator<Item = (&Price, &Volume)>> = if side == Side::Buy {
+            Box::new(self.bids.range(price..))
+        } else {
+            Box::new(self.asks.range(..=price))
+        };
+        let mut depth = Volume::default();
+        for (_, &volume) in levels {
+            depth += volume;
+        }
+        depth
+    }
+
+    /// Average price in dollars to buy or sell the given volume against the book, none if there is not enough depth
+    pub fn vwap_to_fill(&self, side: Side, volume: Volume) -> Option<f64> {
+        let levels: Box<dyn Iterator<Item = (&Price, &Volume)>> = if side == Side::Buy {
+            Box::new(self.asks.iter())
+        } else {
+            Box::new(self.bids.iter().rev())
+        };
+        let mut remaining = volume.0;
+        let mut notional = 0;
+        for (price, level_volume) in levels {
+            let filled = remaining.min(level_volume.0);
+            notional += price.0 as i64 * filled as i64;
+            remaining = remaining.saturating_sub(filled);
+            if remaining == 0 {
+                return (volume != 0).then(|| notional as f64 / volume.0 as f64 / 100.0);
+            }
+        }
+        None
+    }
+
+    pub fn metrics(&self, levels: usize) -> BookMetrics {
+        BookMetrics {
+            mid: self.mid(),
+            microprice: self.microprice(),
+            top_imbalance: self.imbalance(1),
+            imbalance: self.imbalance(levels),
+            levels,
+            spread_ticks: self.spread_ticks(),
+            cross: self.cross,
+            locked_count: self.locked_count,
+            crossed_count: self.crossed_count,
+        }
+    }
+
+    /// Whether the best bid is at or above the best ask
+    pub fn find_cross(&self) -> Option<Cross> {
+        match self.bbo() {
+            (Some(bid), Some(ask)) if bid.price == ask.price => Some(Cross::Locked),
+            (Some(bid), Some(ask)) if bid.price > ask.price => Some(Cross::Crossed),
+            _ => None,
+        }
+    }
+
+    /// Counts the book becoming locked or crossed after a change to its levels
+    fn update_cross(&mut self) {
+        let cross = self.find_cross();
+        if cross != self.cross {
+            match cross {
+                Some(Cross::Locked) => self.locked_count += 1,
+                Some(Cross::Crossed) => self.crossed_count += 1,
+                None => (),
+            }
+        }
+        self.cross = cross;
+    }
+
+    /// Depth of one side of the book without our own resting orders, best price first,
+    /// fails when we have more volume resting at a price than its level holds
+    pub fn others_depth(
+        &self,
+        side: Side,
+    ) -> Result<Box<dyn Iterator<Item = PriceLevel> + '_>, TypeError> {
+        let (levels, own) = if side == Side::Buy {
+            (&self.bids, &self.own_bids)
+        } else {
+            (&self.asks, &self.own_asks)
+        };
+        for (price, &own) in own {
+            levels
+                .get(price)
+                .copied()
+                .unwrap_or_default()
+                .checked_sub(own)?;
+        }
+        let depth: Box<dyn Iterator<Item = (&Price, &Volume)>> = if side == Side::Buy {
+            Box::new(levels.iter().rev())
+        } else {
+            Box::new(levels.iter())
+        };
+        Ok(Box::new(depth.filter_map(|(&price, &volume)| {
+            let volume = volume.saturating_sub(own.get(&price).copied().unwrap_or_default());
+            (volume != 0).then_some(PriceLevel { price, volume })
+        })))
+    }
+
+    /// Queue holding a resting order
+    fn queue(&self, order_id: &str) -> Option<&VecDeque<String>> {
+        let order = self.orders.get(order_id)?;
+        [&self.bid_queues, &self.ask_queues]
+            .into_iter()
+            .filter_map(|queues| queues.get(&order.price))
+            .find(|queue| queue.iter().any(|id| id == order_id))
+    }
+
+    /// Number of orders ahead of a resting order at its price, zero when it is next to trade
+    pub fn queue_position(&self, order_id: &str) -> Option<usize> {
+        let queue = self.queue(order_id)?;
+        queue.iter().position(|id| id == order_id)
+    }
+
+    /// Volume that has to trade at its price before a resting order does, None if the queue names an unknown order
+    pub fn volume_ahead(&self, order_id: &str) -> Option<Volume> {
+        let queue = self.queue(order_id)?;
+        let mut ahead = Volume::default();
+        for id in queue.iter().take_while(|&id| id != order_id) {
+            ahead = ahead.checked_add(self.orders.get(id)?.volume).ok()?;
+        }
+        Some(ahead)
+    }
+
+    /// Our resting orders with how many orders and how much volume is ahead of each, ordered by ID
+    pub fn own_queue_positions(&self, username: &Username) -> Vec<(&str, usize, Volume)> {
+        let mut positions: Vec<_> = self
+            .orders
+            .iter()
+            .filter(|(_, order)| order.owner == *username)
+            .filter_map(|(id, _)| {
+                Some((
+                    id.as_str(),
+                    self.queue_position(id)?,
+                    self.volume_ahead(id)?,
+                ))
+            })
+            .collect();
+        positions.sort();
+        positions
+    }
+
+    /// Applies an order added on the feed, leaving the book untouched if any volume would overflow
+    pub fn add_order(&mut self, added: AddedMessage, username: &Username) -> Result<(), BookError> {
+        let own = added.owner == *username;
         let (side, exposure) = get_side_and_exposure!(self, added.side);
-        if added.owner == *username {
-            *exposure += added.resting;
+        let level = side
+            .get(&added.price)
+            .copied()
+            .unwrap_or_default()
+            .checked_add(added.resting)?;
+        let exposure = if own {
+            exposure.checked_add(added.resting)?
+        } else {
+            *exposure
+        };
+        let own_level = get_own_side!(self, added.side)
+            .get(&added.price)
+            .copied()
+            .unwrap_or_default()
+            .checked_add(added.resting)?;
+
+        self.version += 1;
+        get_queues!(self, added.side)
+            .entry(added.price)
+            .or_default()
+            .push_back(added.id.clone());
+        if own {
+            get_own_side!(self, added.side).insert(added.price, own_level);
         }
-        side.entry(added.price)
-            .and_modify(|volume| *volume += added.resting)
-            .or_insert(added.resting);
+        let (side, side_exposure) = get_side_and_exposure!(self, added.side);
+        *side_exposure = exposure;
+        side.insert(added.price, level);
+        self.infer_tick(added.price);
         self.orders.insert(added.id.clone(), added.into());
+        self.update_cross();
+        Ok(())
     }
 
-    pub fn remove_order(&mut self, deleted: DeletedMessage, username: &Username) {
+    /// Applies an order deleted on the feed, leaving the book untouched if any volume would underflow
+    pub fn remove_order(
+        &mut self,
+        deleted: DeletedMessage,
+        username: &Username,
+    ) -> Result<(), BookError> {
         let order = self
             .orders
-            .remove(&deleted.id)
-            .expect("Deleting an order with unknown ID");
-
+            .get(&deleted.id)
+            .ok_or_else(|| BookError::UnknownOrder(deleted.id.clone()))?;
+        let own = order.owner == *username;
+        let (price, volume) = (order.price, order.volume);
+        let own_level = if own {
+            get_own_side!(self, deleted.side)
+                .get(&price)
+                .expect("Own order does not exist in the own depth")
+                .checked_sub(volume)?
+        } else {
+            Volume(0)
+        };
         let (side, exposure) = get_side_and_exposure!(self, deleted.side);
-        if order.owner == *username {
-            *exposure -= order.volume;
+        let exposure = if own {
+            exposure.checked_sub(volume)?
+        } else {
+            *exposure
+        };
+        let level = side
+            .get(&price)
+            .expect("Order does not exist in the orderbook")
+            .checked_sub(volume)?;
+
+        self.version += 1;
+        self.orders.remove(&deleted.id);
+        let queues = get_queues!(self, deleted.side);
+        let queue = queues
+            .get_mut(&price)
+            .expect("Order does not exist in the queues");
+        queue.retain(|id| *id != deleted.id);
+        if queue.is_empty() {
+            queues.remove(&price);
+        }
+        if own {
+            let own_side = get_own_side!(self, deleted.side);
+            if own_level == 0 {
+                own_side.remove(&price);
+            } else {
+                own_side.insert(price, own_level);
+            }
+        }
+        let (side, side_exposure) = get_side_and_exposure!(self, deleted.side);
+        *side_exposure = exposure;
+        if level == 0 {
+            side.remove(&price);
+        } else {
+            side.insert(price, level);
+        }
+        self.update_cross();
+        Ok(())
+    }
+
+    /// Applies a trade on the feed, leaving the book untouched if our position or any volume would overflow
+    pub fn trade(&mut self, trade: TradeMessage, username: &Username) -> Result<(), BookError> {
+        let signed = trade.volume.signed()?;
+        let mut position = self.position.position;
+        if trade.buyer == *username {
+            position = position
+                .checked_add(signed)
+                .ok_or(TypeError::Overflow("Position"))?;
+        }
+        if trade.seller == *username {
+            position = position
+                .checked_sub(signed)
+                .ok_or(TypeError::Overflow("Position"))?;
+        }
+        if trade.trade_type == TradeType::BrokerTrade {
+            // Arranged off the book, so no resting order is touched but both sides pay the broker
+            let sides = (trade.buyer == *username) as i64 + (trade.seller == *username) as i64;
+            let fees = (sides * self.broker_fee.0 as i64)
+                .checked_mul(trade.volume.0 as i64)
+                .and_then(|fees| self.fees.checked_add(fees))
+                .ok_or(TypeError::Overflow("Broker fees"))?;
+            self.version += 1;
+            self.position.position = position;
+            self.fees = fees;
+            return Ok(());
+        }
+        let Some(order) = self.orders.get(&trade.passive_order) else {
+            self.version += 1;
+            self.position.position = position;
+            return Ok(());
+        };
+        let remaining = order.volume.checked_sub(trade.volume)?;
+        if remaining != trade.passive_order_remaining || order.price != trade.price {
+            return Err(BookError::PassiveOrderMismatch(trade.passive_order));
+        }
+
+        let side = if trade.trade_type == TradeType::BuyAggressor {
+            Side::Sell
+        } else {
+            Side::Buy
+        };
+        if trade.passive_order_remaining == 0 {
+            self.remove_order(
+                DeletedMessage {
+                    product: trade.product,
+                    id: trade.passive_order,
+                    side,
+                    sequence: trade.sequence,
+                },
+                username,
+            )?;
+            self.position.position = position;
+            return Ok(());
         }
+        let own = order.owner == *username;
+        let price = order.price;
+        let own_level = if own {
+            get_own_side!(self, side)
+                .get(&price)
+                .expect("Own order does not exist in the own depth")
+                .checked_sub(trade.volume)?
+        } else {
+            Volume(0)
+        };
+        let (levels, exposure) = get_side_and_exposure!(self, side);
+        let exposure = if own {
+            exposure.checked_sub(trade.volume)?
+        } else {
+            *exposure
+        };
+        let level = levels
+            .get(&price)
+            .expect("Executing an order with a price not in the orderbook")
+            .checked_sub(trade.volume)?;
 
-        let volume = side
-            .get_mut(&order.price)
-            .expect("Order does not exist in the orderbook");
-        *volume -= order.volume;
-        if *volume == 0 {
-            side.remove(&order.price);
+        self.version += 1;
+        self.position.position = position;
+        if own {
+            get_own_side!(self, side).insert(price, own_level);
         }
+        let (levels, side_exposure) = get_side_and_exposure!(self, side);
+        *side_exposure = exposure;
+        levels.insert(price, level);
+        self.orders
+            .get_mut(&trade.passive_order)
+            .expect("Passive order exists")
+            .volume = remaining;
+        Ok(())
     }
+}
 
-    pub fn trade(&mut self, trade: TradeMessage, username: &Username) {
-        if trade.buyer == *username || trade.seller == *username {
-            if trade.buyer == *username {
-                self.position.position += trade.volume;
-            }
-            if trade.seller == *username {
-                self.position.position -= trade.volume;
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static PRODUCT: &str = "F_SOP_APP0104T0950";
+
+    fn added(id: &str, side: Side, price: i32, resting: u32, owner: Username) -> AddedMessage {
+        AddedMessage {
+            product: PRODUCT.to_string(),
+            id: id.to_string(),
+            side,
+            price: Price(price),
+            filled: Volume(0),
+            resting: Volume(resting),
+            owner,
+            sequence: 0,
         }
-        if let Some(order) = self.orders.get_mut(&trade.passive_order) {
-            assert_eq!(order.volume - trade.volume, trade.passive_order_remaining, "Remaining passive order in the trade message is not equal to the remaining order in the orderbook");
-            assert_eq!(order.price, trade.price, "Passive order in the trade message has different price than the order in the orderbook");
-
-            if trade.trade_type != TradeType::BrokerTrade {
-                let side = if trade.trade_type == TradeType::BuyAggressor {
-                    Side::Sell
-                } else {
-                    Side::Buy
-                };
-                if trade.passive_order_remaining == 0 {
-                    self.remove_order(
-                        DeletedMessage {
-                            product: trade.product,
-                            id: trade.passive_order,
-                            side,
-                            sequence: trade.sequence,
-                        },
-                        username,
-                    );
-                } else {
-                    let (side, exposure) = get_side_and_exposure!(self, side);
-                    order.volume -= trade.volume;
-
-                    if order.owner == *username {
-                        *exposure -= trade.volume;
-                    }
-
-                    let volume = side
-                        .get_mut(&order.price)
-                        .expect("Executing an order with a price not in the orderbook");
-                    *volume -= trade.volume;
-                }
-            }
+    }
+
+    fn trade(passive_order: &str, price: i32, volume: u32, remaining: u32) -> TradeMessage {
+        TradeMessage {
+            product: PRODUCT.to_string(),
+            price: Price(price),
+            volume: Volume(volume),
+            buyer: Username::PRao,
+            seller: Username::CChuah,
+            trade_type: TradeType::BuyAggressor,
+            passive_order: passive_order.to_string(),
+            passive_order_remaining: Volume(remaining),
+            aggressor_order: String::from("aggressor"),
+            sequence: 0,
+        }
+    }
+
+    #[test]
+    fn test_queue_priority() {
+        let username = Username::KLiang;
+        let mut book = Book::new(PRODUCT.to_string(), Station::SydOlympicPark, String::new());
+        book.add_order(
+            added("1", Side::Sell, 1000, 10, Username::CChuah),
+            &username,
+        )
+        .expect("Message applies to the book");
+        book.add_order(added("2", Side::Sell, 1000, 5, Username::PRao), &username)
+            .expect("Message applies to the book");
+        book.add_order(added("3", Side::Sell, 1000, 7, Username::KLiang), &username)
+            .expect("Message applies to the book");
+        book.add_order(added("4", Side::Sell, 1100, 3, Username::PRao), &username)
+            .expect("Message applies to the book");
+        book.add_order(added("5", Side::Buy, 900, 4, Username::KLiang), &username)
+            .expect("Message applies to the book");
+        assert_eq!(book.queue_position("3"), Some(2));
+        assert_eq!(book.volume_ahead("3"), Some(Volume(15)));
+        assert_eq!(
+            book.own_queue_positions(&username),
+            vec![("3", 2, Volume(15)), ("5", 0, Volume(0))],
+        );
+
+        // A partial fill keeps priority
+        book.trade(trade("1", 1000, 4, 6), &username)
+            .expect("Message applies to the book");
+        assert_eq!(book.queue_position("1"), Some(0));
+        assert_eq!(book.volume_ahead("3"), Some(Volume(11)));
+
+        book.trade(trade("1", 1000, 6, 0), &username)
+            .expect("Message applies to the book");
+        book.remove_order(
+            DeletedMessage {
+                product: PRODUCT.to_string(),
+                id: String::from("2"),
+                side: Side::Sell,
+                sequence: 0,
+            },
+            &username,
+        )
+        .expect("Message applies to the book");
+        assert_eq!(book.queue_position("3"), Some(0));
+        assert_eq!(book.volume_ahead("3"), Some(Volume(0)));
+        assert_eq!(book.queue_position("2"), None);
+        // Messages out of step with the book are reported and leave it as it was
+        let before = book.clone();
+        assert_eq!(
+            book.remove_order(
+                DeletedMessage {
+                    product: PRODUCT.to_string(),
+                    id: String::from("2"),
+                    side: Side::Sell,
+                    sequence: 0,
+                },
+                &username,
+            ),
+            Err(BookError::UnknownOrder(String::from("2"))),
+        );
+        assert_eq!(
+            book.trade(trade("3", 1000, 4, 4), &username),
+            Err(BookError::PassiveOrderMismatch(String::from("3"))),
+        );
+        assert_eq!(book, before);
+        assert_eq!(book.version, before.version);
+        assert_eq!(
+            book.ask_queues,
+            BTreeMap::from([
+                (Price(1000), VecDeque::from([String::from("3")])),
+                (Price(1100), VecDeque::from([String::from("4")])),
+            ]),
+        );
+
+        // A queue out of step with the orders is reported rather than trusted
+        book.add_order(added("6", Side::Sell, 1000, 2, Username::PRao), &username)
+            .expect("Message applies to the book");
+        book.orders.remove("3");
+        assert_eq!(book.volume_ahead("6"), None);
+    }
+
+    #[test]
+    fn test_broker_trade() {
+        let username = Username::KLiang;
+        let mut book = Book {
+            broker_fee: Price(5),
+            ..Book::new(PRODUCT.to_string(), Station::SydOlympicPark, String::new())
+        };
+        book.add_order(
+            added("1", Side::Sell, 1000, 10, Username::CChuah),
+            &username,
+        )
+        .expect("Message applies to the book");
+        let depth = book.clone();
+        let broker_trade = |buyer, seller| TradeMessage {
+            trade_type: TradeType::BrokerTrade,
+            buyer,
+            seller,
+            passive_order: String::new(),
+            ..trade("1", 1000, 10, 0)
+        };
+
+        book.trade(broker_trade(Username::KLiang, Username::PRao), &username)
+            .expect("Message applies to the book");
+        assert_eq!(book.position.position, 10);
+        assert_eq!(book.fees, 50);
+        assert_eq!((&book.asks, &book.orders), (&depth.asks, &depth.orders));
+        assert_eq!(book.version, depth.version + 1);
+
+        // Both sides of a wash pay the broker
+        book.trade(broker_trade(Username::KLiang, Username::KLiang), &username)
+            .expect("Message applies to the book");
+        assert_eq!(book.position.position, 10);
+        assert_eq!(book.fees, 150);
+
+        book.trade(broker_trade(Username::PRao, Username::CChuah), &username)
+            .expect("Message applies to the book");
+        assert_eq!(book.position.position, 10);
+        assert_eq!(book.fees, 150);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let book = Book {
+            bids: BTreeMap::from([(Price(1000), Volume(30)), (Price(990), Volume(50))]),
+            asks: BTreeMap::from([(Price(1004), Volume(10)), (Price(1010), Volume(20))]),
+            ..Default::default()
+        };
+        assert_eq!(book.mid(), Some(10.02));
+        // Heavier bid pushes the microprice towards the ask
+        assert_eq!(book.microprice(), Some(10.03));
+        assert_eq!(book.imbalance(1), Some(0.5));
+        assert_eq!(book.imbalance(2), Some(50.0 / 110.0));
+        assert_eq!(book.spread_ticks(), Some(4));
+        assert_eq!(book.depth_to(Side::Buy, Price(990)), Volume(80));
+        assert_eq!(book.depth_to(Side::Buy, Price(995)), Volume(30));
+        assert_eq!(book.depth_to(Side::Sell, Price(1004)), Volume(10));
+        assert_eq!(book.vwap_to_fill(Side::Buy, Volume(20)), Some(10.07));
+        assert_eq!(book.vwap_to_fill(Side::Sell, Volume(30)), Some(10.0));
+        assert_eq!(book.vwap_to_fill(Side::Buy, Volume(31)), None);
+    }
+
+    #[test]
+    fn test_metrics_one_sided() {
+        let book = Book {
+            bids: BTreeMap::from([(Price(1000), Volume(30))]),
+            ..Default::default()
+        };
+        assert_eq!(book.mid(), None);
+        assert_eq!(book.microprice(), None);
+        assert_eq!(book.imbalance(3), Some(1.0));
+        assert_eq!(book.spread_ticks(), None);
+        assert_eq!(Book::default().imbalance(1), None);
+        assert_eq!(
+            book.metrics(3).to_string(),
+            "mid - micro - imbalance top 1.000 3 levels 1.000 spread - uncrossed, locked 0 crossed 0 times",
+        );
+    }
+
+    #[test]
+    fn test_cross() {
+        let username = Username::KLiang;
+        let mut book = Book::new(PRODUCT.to_string(), Station::SydOlympicPark, String::new());
+        book.add_order(added("1", Side::Sell, 1000, 10, Username::PRao), &username)
+            .expect("Message applies to the book");
+        book.add_order(added("2", Side::Buy, 990, 5, Username::CChuah), &username)
+            .expect("Message applies to the book");
+        assert_eq!(book.cross, None);
+
+        book.add_order(added("3", Side::Buy, 1000, 5, Username::CChuah), &username)
+            .expect("Message applies to the book");
+        assert_eq!(book.cross, Some(Cross::Locked));
+        book.add_order(added("4", Side::Buy, 1010, 5, Username::CChuah), &username)
+            .expect("Message applies to the book");
+        assert_eq!(book.cross, Some(Cross::Crossed));
+        // The trade that should have uncrossed the book
+        book.trade(trade("1", 1000, 5, 5), &username)
+            .expect("Message applies to the book");
+        assert_eq!(book.cross, Some(Cross::Crossed));
+        for id in ["4", "3"] {
+            book.remove_order(
+                DeletedMessage {
+                    product: PRODUCT.to_string(),
+                    id: id.to_string(),
+                    side: Side::Buy,
+                    sequence: 0,
+                },
+                &username,
+            )
+            .expect("Message applies to the book");
         }
+        assert_eq!(book.cross, None);
+        // Locked again on the way out after the best bid was deleted
+        let metrics = book.metrics(1);
+        assert_eq!(
+            (metrics.cross, metrics.locked_count, metrics.crossed_count),
+            (None, 2, 1),
+        );
+    }
+
+    #[test]
+    fn test_tick_size() {
+        let username = Username::KLiang;
+        let mut book = Book::new(PRODUCT.to_string(), Station::SydOlympicPark, String::new());
+        assert_eq!(book.tick(), Book::DEFAULT_TICK_SIZE);
+        book.add_order(added("1", Side::Sell, 1050, 10, Username::PRao), &username)
+            .expect("Message applies to the book");
+        assert_eq!(book.tick(), Book::DEFAULT_TICK_SIZE);
+        book.add_order(added("2", Side::Buy, -100, 10, Username::PRao), &username)
+            .expect("Message applies to the book");
+        book.add_order(added("3", Side::Buy, 900, 10, Username::PRao), &username)
+            .expect("Message applies to the book");
+        book.add_order(added("4", Side::Sell, 1050, 10, Username::PRao), &username)
+            .expect("Message applies to the book");
+        book.add_order(added("5", Side::Sell, 1100, 10, Username::PRao), &username)
+            .expect("Message applies to the book");
+        // Only four distinct prices, too few to trust their common divisor
+        assert_eq!(book.inferred_tick, Some(Price(50)));
+        assert_eq!(book.tick(), Book::DEFAULT_TICK_SIZE);
+        book.add_order(added("6", Side::Sell, 1200, 10, Username::PRao), &username)
+            .expect("Message applies to the book");
+        assert_eq!(book.tick(), Price(50));
+        assert_eq!(book.spread_ticks(), Some(3));
+        assert!(book.on_grid(Price(-150)));
+        assert!(!book.on_grid(Price(1020)));
+
+        assert_eq!(book.round_passive(Side::Buy, Price(1020)), Ok(Price(1000)));
+        assert_eq!(book.round_passive(Side::Sell, Price(1020)), Ok(Price(1050)));
+        assert_eq!(
+            book.round_aggressive(Side::Buy, Price(-120)),
+            Ok(Price(-100))
+        );
+        assert_eq!(
+            book.round_aggressive(Side::Sell, Price(-120)),
+            Ok(Price(-150))
+        );
+        assert_eq!(
+            book.round_aggressive(Side::Sell, Price(1000)),
+            Ok(Price(1000))
+        );
+        assert!(book.round_aggressive(Side::Buy, Price::MAX).is_err());
+
+        // A configured tick size wins over the inferred one
+        book.tick_size = Some(Price(5));
+        assert_eq!(book.round_passive(Side::Buy, Price(1022)), Ok(Price(1020)));
     }
 }
@@ -0,0 +1,285 @@
+//! Arbitrage parameters loaded from a JSON file.
+
+use crate::{
+    arbitrage::{OffGrid, Sweep},
+    types::{Price, Volume},
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Parameters used to look for arbs on a single index
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndexParams {
+    /// Minimum edge between the index and its theo before an arb is taken
+    pub credit: Price,
+    /// Largest volume traded on each leg of an arb
+    pub max_volume: Volume,
+    pub sweep: Sweep,
+    pub off_grid: OffGrid,
+}
+
+impl Default for IndexParams {
+    fn default() -> Self {
+        IndexParams {
+            credit: Price(500),
+            max_volume: Volume(100),
+            sweep: Sweep::WorstPrice,
+            off_grid: OffGrid::Reject,
+        }
+    }
+}
+
+/// Parameters of an index that differ from the defaults
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct IndexOverride {
+    pub credit: Option<Price>,
+    pub max_volume: Option<Volume>,
+    pub sweep: Option<Sweep>,
+    pub off_grid: Option<OffGrid>,
+}
+
+/// Arbitrage parameters, loaded from a JSON file such as
+/// `{"credit": 5.0, "indices": {"2024-01-04 09:50+1100": {"maxVolume": 50}}, "positionLimits": {"F_SOP_APP0104T0950": 500}}`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+pub struct ArbitrageConfig {
+    pub credit: Price,
+    pub max_volume: Volume,
+    pub sweep: Sweep,
+    pub off_grid: OffGrid,
+    pub position_limit: i32,
+    /// Overrides keyed by the expiry of the index
+    pub indices: HashMap<String, IndexOverride>,
+    /// Position limits keyed by product
+    pub position_limits: HashMap<String, i32>,
+}
+
+impl Default for ArbitrageConfig {
+    fn default() -> Self {
+        let params = IndexParams::default();
+        ArbitrageConfig {
+            credit: params.credit,
+            max_volume: params.max_volume,
+            sweep: params.sweep,
+            off_grid: params.off_grid,
+            position_limit: 1000,
+            indices: HashMap::new(),
+            position_limits: HashMap::new(),
+        }
+    }
+}
+
+impl ArbitrageConfig {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let config: ArbitrageConfig =
+            serde_json::from_slice(&fs::read(path).map_err(ConfigError::Io)?)
+                .map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let expiries = || std::iter::once("default").chain(self.indices.keys().map(String::as_str));
+        if let Some(expiry) = expiries().find(|expiry| self.index(expiry).max_volume == 0) {
+            return Err(ConfigError::Invalid(format!(
+                "Volume cap of {expiry} must be positive",
+            )));
+        }
+        if let Some(expiry) = expiries().find(|expiry| self.index(expiry).credit < Price(0)) {
+            return Err(ConfigError::Invalid(format!(
+                "Credit of {expiry} must not be negative",
+            )));
+        }
+        if let Some((product, limit)) = std::iter::once(("default", &self.position_limit))
+            .chain(
+                self.position_limits
+                    .iter()
+                    .map(|(product, limit)| (product.as_str(), limit)),
+            )
+            .find(|(_, &limit)| limit <= 0)
+        {
+            return Err(ConfigError::Invalid(format!(
+                "Position limit {limit} of {product} must be positive",
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn index(&self, expiry: &str) -> IndexParams {
+        let overrides = self.indices.get(expiry);
+        IndexParams {
+            credit: overrides
+                .and_then(|overrides| overrides.credit)
+                .unwrap_or(self.credit),
+            max_volume: overrides
+                .and_then(|overrides| overrides.max_volume)
+                .unwrap_or(self.max_volume),
+            sweep: overrides
+                .and_then(|overrides| overrides.sweep)
+                .unwrap_or(self.sweep),
+            off_grid: overrides
+                .and_then(|overrides| overrides.off_grid)
+                .unwrap_or(self.off_grid),
+        }
+    }
+
+    pub fn position_limit(&self, product: &str) -> i32 {
+        *self
+            .position_limits
+            .get(product)
+            .unwrap_or(&self.position_limit)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(serde_json::Error),
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "failed to read config: {err}"),
+            ConfigError::Parse(err) => write!(f, "failed to parse config: {err}"),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Reloads a config file whenever its modification time changes
+#[derive(Debug)]
+pub struct ConfigWatcher {
+    pub path: PathBuf,
+    /// Modification time when the file was last loaded, none if it could not be read
+    modified: Option<SystemTime>,
+    loaded: bool,
+}
+
+impl ConfigWatcher {
+    /// Loads the config for the first time
+    pub fn new(path: PathBuf) -> Result<(Self, ArbitrageConfig), ConfigError> {
+        let mut watcher = ConfigWatcher {
+            path,
+            modified: None,
+            loaded: false,
+        };
+        let config = watcher.poll().expect("Config has not been loaded before")?;
+        Ok((watcher, config))
+    }
+
+    /// The new config if the file changed since it was last loaded, a config that fails to load is not retried until the file changes again
+    pub fn poll(&mut self) -> Option<Result<ArbitrageConfig, ConfigError>> {
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if self.loaded && self.modified == modified {
+            return None;
+        }
+        self.loaded = true;
+        self.modified = modified;
+        Some(ArbitrageConfig::load(&self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    static EXPIRY: &str = "2024-01-04 09:50+1100";
+    static PRODUCT: &str = "F_SOP_APP0104T0950";
+
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("bomex_{}_{name}.json", std::process::id()));
+        fs::write(&path, contents).expect("Failed to write config");
+        path
+    }
+
+    #[test]
+    fn test_overrides() {
+        let config: ArbitrageConfig = serde_json::from_str(&format!(
+            r#"{{
+                "credit": 3.0,
+                "indices": {{"{EXPIRY}": {{"maxVolume": 50, "sweep": "perLevel", "offGrid": "round"}}}},
+                "positionLimits": {{"{PRODUCT}": 500}}
+            }}"#
+        ))
+        .expect("Failed to parse config");
+        assert_eq!(
+            config.index(EXPIRY),
+            IndexParams {
+                credit: Price(300),
+                max_volume: Volume(50),
+                sweep: Sweep::PerLevel,
+                off_grid: OffGrid::Round,
+            },
+        );
+        assert_eq!(
+            config.index("other"),
+            IndexParams {
+                credit: Price(300),
+                max_volume: Volume(100),
+                sweep: Sweep::WorstPrice,
+                off_grid: OffGrid::Reject,
+            },
+        );
+        assert_eq!(config.position_limit(PRODUCT), 500);
+        assert_eq!(config.position_limit("other"), 1000);
+    }
+
+    #[test]
+    fn test_invalid() {
+        for contents in [
+            r#"{"maxVolume": 0}"#,
+            r#"{"credit": -0.01}"#,
+            r#"{"indices": {"2024-01-04 09:50+1100": {"credit": -5.0}}}"#,
+            r#"{"positionLimits": {"F_SOP_APP0104T0950": -1}}"#,
+            r#"{"credits": 5.0}"#,
+        ] {
+            let path = config_file("invalid", contents);
+            assert!(ArbitrageConfig::load(&path).is_err(), "{contents}");
+            fs::remove_file(path).expect("Failed to remove config");
+        }
+    }
+
+    #[test]
+    fn test_reload() {
+        let path = config_file("reload", r#"{"credit": 5.0}"#);
+        let (mut watcher, config) = ConfigWatcher::new(path.clone()).expect("Failed to load");
+        assert_eq!(config.credit, Price(500));
+        assert!(watcher.poll().is_none());
+
+        let touch = |contents: &str, offset| {
+            fs::write(&path, contents).expect("Failed to write config");
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_modified(SystemTime::now() + Duration::from_secs(offset)))
+                .expect("Failed to touch config");
+        };
+        touch(r#"{"credit": 2.0}"#, 1);
+        let config = watcher
+            .poll()
+            .expect("Config changed")
+            .expect("Valid config");
+        assert_eq!(config.credit, Price(200));
+        assert!(watcher.poll().is_none());
+
+        // A broken config is reported once and kept out until fixed
+        touch("{", 2);
+        assert!(watcher.poll().expect("Config changed").is_err());
+        assert!(watcher.poll().is_none());
+        fs::remove_file(path).expect("Failed to remove config");
+    }
+}
@@ -0,0 +1,229 @@
+//! Statistics on the other traders inferred from the feed, to tell who the informed ones are.
+
+use crate::{
+    book::Book,
+    feed::{AddedMessage, TradeMessage},
+    types::Volume,
+    username::Username,
+};
+use std::collections::HashMap;
+use tracing::warn;
+
+/// What the feed tells us about one trader
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CounterpartyStats {
+    pub trades: u32,
+    pub volume: u32,
+    /// Volume traded with us on the other side
+    pub volume_against_us: u32,
+    pub orders_added: u32,
+    pub volume_added: u32,
+    /// Net position per product inferred from every trade they were part of
+    pub positions: HashMap<String, i32>,
+}
+
+impl CounterpartyStats {
+    fn fill(&mut self, product: &str, volume: i32) {
+        let position = self.positions.entry(product.to_string()).or_default();
+        *position = position.saturating_add(volume);
+    }
+}
+
+/// Per trader statistics, to tell who the informed traders are
+#[derive(Debug)]
+pub struct Counterparties {
+    pub username: Username,
+    pub stats: HashMap<Username, CounterpartyStats>,
+}
+
+impl Counterparties {
+    pub fn new(username: Username) -> Self {
+        Counterparties {
+            username,
+            stats: HashMap::new(),
+        }
+    }
+
+    pub fn added(&mut self, added: &AddedMessage) {
+        let stats = self.stats.entry(added.owner.clone()).or_default();
+        stats.orders_added = stats.orders_added.saturating_add(1);
+        stats.volume_added = stats
+            .volume_added
+            .saturating_add(added.filled.0)
+            .saturating_add(added.resting.0);
+    }
+
+    pub fn trade(&mut self, trade: &TradeMessage) {
+        let volume = match trade.volume.signed() {
+            Ok(volume) => volume,
+            Err(err) => {
+                warn!(error = %err, ?trade, "Ignoring trade for counterparty statistics");
+                return;
+            }
+        };
+        for (username, other, signed) in [
+            (&trade.buyer, &trade.seller, volume),
+            (&trade.seller, &trade.buyer, -volume),
+        ] {
+            let stats = self.stats.entry(username.clone()).or_default();
+            stats.trades = stats.trades.saturating_add(1);
+            stats.volume = stats.volume.saturating_add(trade.volume.0);
+            if *other == self.username && *username != self.username {
+                stats.volume_against_us = stats.volume_against_us.saturating_add(trade.volume.0);
+            }
+            stats.fill(&trade.product, signed);
+        }
+    }
+
+    /// Volume each trader currently has resting across the books
+    pub fn resting<'a>(books: impl Iterator<Item = &'a Book>) -> HashMap<Username, Volume> {
+        let mut resting: HashMap<Username, Volume> = HashMap::new();
+        for order in books.flat_map(|book| book.orders.values()) {
+            *resting.entry(order.owner.clone()).or_default() += order.volume;
+        }
+        resting
+    }
+
+    /// One line per trader other than us, most traded against us first, with how the price moved
+    /// in their favour after trading with us as given by `Markouts::by_counterparty`
+    pub fn report<'a>(
+        &self,
+        books: impl Iterator<Item = &'a Book>,
+        markouts: &HashMap<Username, (f64, u32)>,
+    ) -> String {
+        let resting = Counterparties::resting(books);
+        let mut stats: Vec<_> = self
+            .stats
+            .iter()
+            .filter(|(username, _)| **username != self.username)
+            .collect();
+        stats.sort_by(|a, b| {
+            b.1.volume_against_us
+                .cmp(&a.1.volume_against_us)
+                .then(b.1.volume.cmp(&a.1.volume))
+        });
+        stats
+            .into_iter()
+            .map(|(username, stats)| {
+                let mut positions: Vec<_> = stats
+                    .positions
+                    .iter()
+                    .filter(|(_, &position)| position != 0)
+                    .collect();
+                positions.sort();
+                format!(
+                    "{username:?}: {} trades, {} lots, {} against us, markout {}, {} orders added, {:?} resting, positions {positions:?}\n",
+                    stats.trades,
+                    stats.volume,
+                    stats.volume_against_us,
+                    match markouts.get(username) {
+                        Some(&(total, volume)) if volume != 0 => {
+                            format!("{total:.2} ({:.3}/lot)", total / volume as f64)
+                        }
+                        _ => String::from("-"),
+                    },
+                    stats.orders_added,
+                    resting.get(username).copied().unwrap_or_default(),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        book::Order,
+        feed::TradeType,
+        types::{Price, Side, Volume},
+    };
+
+    static PRODUCT: &str = "F_SOP_APP0104T0950";
+
+    fn trade(price: i32, volume: u32, buyer: Username, seller: Username) -> TradeMessage {
+        TradeMessage {
+            product: PRODUCT.to_string(),
+            price: Price(price),
+            volume: Volume(volume),
+            buyer,
+            seller,
+            trade_type: TradeType::BuyAggressor,
+            passive_order: String::from("1"),
+            passive_order_remaining: Volume(0),
+            aggressor_order: String::from("2"),
+            sequence: 1,
+        }
+    }
+
+    #[test]
+    fn test_counterparties() {
+        let mut counterparties = Counterparties::new(Username::KLiang);
+        counterparties.trade(&trade(1000, 10, Username::PRao, Username::KLiang));
+        counterparties.trade(&trade(1100, 4, Username::CChuah, Username::PRao));
+        counterparties.added(&AddedMessage {
+            product: PRODUCT.to_string(),
+            id: String::from("3"),
+            side: Side::Sell,
+            price: Price(1200),
+            filled: Volume(2),
+            resting: Volume(5),
+            owner: Username::PRao,
+            sequence: 2,
+        });
+
+        let prao = counterparties
+            .stats
+            .get(&Username::PRao)
+            .expect("Has traded");
+        assert_eq!(prao.trades, 2);
+        assert_eq!(prao.volume, 14);
+        assert_eq!(prao.volume_against_us, 10);
+        assert_eq!(prao.orders_added, 1);
+        assert_eq!(prao.volume_added, 7);
+        assert_eq!(prao.positions, HashMap::from([(PRODUCT.to_string(), 6)]));
+
+        let cchuah = counterparties
+            .stats
+            .get(&Username::CChuah)
+            .expect("Has traded");
+        assert_eq!(cchuah.volume_against_us, 0);
+        assert_eq!(
+            counterparties.report(
+                std::iter::empty(),
+                &HashMap::from([(Username::PRao, (-2.0, 20))]),
+            ),
+            "PRao: 2 trades, 14 lots, 10 against us, markout -2.00 (-0.100/lot), 1 orders added, 0 resting, positions [(\"F_SOP_APP0104T0950\", 6)]\n\
+             CChuah: 1 trades, 4 lots, 0 against us, markout -, 0 orders added, 0 resting, positions [(\"F_SOP_APP0104T0950\", 4)]\n",
+        );
+    }
+
+    #[test]
+    fn test_resting() {
+        let book = Book {
+            orders: HashMap::from([
+                (
+                    String::from("1"),
+                    Order {
+                        owner: Username::PRao,
+                        price: Price(1000),
+                        volume: Volume(5),
+                    },
+                ),
+                (
+                    String::from("2"),
+                    Order {
+                        owner: Username::PRao,
+                        price: Price(1100),
+                        volume: Volume(3),
+                    },
+                ),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(
+            Counterparties::resting([&book, &book].into_iter()),
+            HashMap::from([(Username::PRao, Volume(16))]),
+        );
+    }
+}
@@ -0,0 +1,891 @@
+//! Sending the orders of the strategies to the exchange and tracking them until the feed confirms them.
+
+use crate::{
+    autotrader::AutoTrader,
+    feed::{TradeMessage, TradeType},
+    fills::PendingFills,
+    latency::OrderLatency,
+    metrics::Metrics,
+    order::{AddMessage, OrderAddedMessage},
+    strategy::{Attribution, OrderAck, StrategyEvent},
+    types::{Price, Side},
+    url,
+    username::Username,
+    watchdog::{BookStates, DisableReason, WatchdogConfig},
+};
+use reqwest::StatusCode;
+use serde_json::{from_str, to_string};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    select, spawn,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+macro_rules! send_order {
+    ($client:expr, $username:expr, $password:expr, $message:expr) => {
+        $client
+            .post(url!(EXECUTION_PORT, "execution"))
+            .form(&[
+                ("username", $username),
+                ("password", $password),
+                (
+                    "message",
+                    &to_string($message).expect("Failed to serializase AddMessage"),
+                ),
+            ])
+            .send()
+            .await
+    };
+}
+
+#[derive(Debug, Clone)]
+pub struct ExecutionConfig {
+    /// Maximum time to establish a connection to the execution server
+    pub connect_timeout: Duration,
+    /// Maximum time for the whole request including reading the response body
+    pub request_timeout: Duration,
+    /// Number of times an order is resent after failing to connect
+    pub connect_retries: u32,
+    pub retry_backoff: Duration,
+    /// How long to wait on the feed for an order with an unknown outcome before assuming it never traded
+    pub confirmation_timeout: Duration,
+}
+
+impl Default for ExecutionConfig {
+    fn default() -> Self {
+        ExecutionConfig {
+            connect_timeout: Duration::from_millis(500),
+            request_timeout: Duration::from_secs(2),
+            connect_retries: 2,
+            retry_backoff: Duration::from_millis(50),
+            confirmation_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl ExecutionConfig {
+    /// Longest an order request can take including every connect retry
+    pub fn max_order_duration(&self) -> Duration {
+        (self.request_timeout + self.retry_backoff * self.connect_retries)
+            * (self.connect_retries + 1)
+    }
+
+    pub fn client(&self) -> reqwest::Client {
+        reqwest::Client::builder()
+            .pool_idle_timeout(None)
+            .tcp_keepalive(Duration::from_secs(30))
+            .tcp_nodelay(true)
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.request_timeout)
+            .build()
+            .expect("Failed to build execution client")
+    }
+}
+
+#[derive(Debug)]
+pub enum OrderError {
+    /// Could not connect to the execution server so the order was never sent
+    Connect(reqwest::Error),
+    /// The request was sent but no response arrived in time
+    Timeout(reqwest::Error),
+    /// The exchange answered with a non success status code
+    Http(StatusCode, String),
+    /// The response could not be parsed as an `OrderAddedMessage`
+    Unparsable(String, serde_json::Error),
+    /// Any other failure after the request may have been sent
+    Request(reqwest::Error),
+}
+
+impl OrderError {
+    fn classify(err: reqwest::Error) -> Self {
+        if err.is_connect() {
+            OrderError::Connect(err)
+        } else if err.is_timeout() {
+            OrderError::Timeout(err)
+        } else {
+            OrderError::Request(err)
+        }
+    }
+
+    /// Whether the order could have reached the exchange, in which case it must not be resent
+    pub fn may_have_traded(&self) -> bool {
+        match self {
+            OrderError::Connect(_) => false,
+            OrderError::Http(status, _) => status.is_server_error(),
+            OrderError::Timeout(_) | OrderError::Unparsable(..) | OrderError::Request(_) => true,
+        }
+    }
+}
+
+impl Display for OrderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OrderError::Connect(err) => write!(f, "failed to connect: {err}"),
+            OrderError::Timeout(err) => write!(f, "timed out: {err}"),
+            OrderError::Http(status, body) => write!(f, "HTTP {status}: {body}"),
+            OrderError::Unparsable(body, err) => write!(f, "unparsable response {body}: {err}"),
+            OrderError::Request(err) => write!(f, "request failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for OrderError {}
+
+/// Sends an order, only resending it when the previous attempt could not have reached the exchange
+pub async fn send_order(
+    client: &reqwest::Client,
+    config: &ExecutionConfig,
+    username: &Username,
+    password: &str,
+    order: &AddMessage,
+    latency: &Arc<Mutex<OrderLatency>>,
+) -> Result<OrderAddedMessage, OrderError> {
+    let username = to_string(username).expect("Failed to convert username to string");
+    let username = username.trim_matches('"');
+    let submitted = Instant::now();
+    latency.lock().unwrap().submit();
+    let result = post_order(
+        client, config, username, password, order, latency, submitted,
+    )
+    .await;
+    latency.lock().unwrap().acknowledge(
+        submitted,
+        result
+            .as_ref()
+            .ok()
+            .map(|added| (added.id.as_str(), added.filled > 0)),
+    );
+    result
+}
+
+async fn post_order(
+    client: &reqwest::Client,
+    config: &ExecutionConfig,
+    username: &str,
+    password: &str,
+    order: &AddMessage,
+    latency: &Arc<Mutex<OrderLatency>>,
+    submitted: Instant,
+) -> Result<OrderAddedMessage, OrderError> {
+    let mut attempt = 0;
+    let response = loop {
+        match send_order!(client, username, password, order) {
+            Ok(response) => break response,
+            Err(err) => match OrderError::classify(err) {
+                OrderError::Connect(err) if attempt < config.connect_retries => {
+                    attempt += 1;
+                    warn!(attempt, error = %err, "Failed to connect, resending the order");
+                    tokio::time::sleep(config.retry_backoff * attempt).await;
+                }
+                err => return Err(err),
+            },
+        }
+    };
+    latency.lock().unwrap().record_response(submitted);
+    let status = response.status();
+    let body = response.text().await.map_err(OrderError::classify)?;
+    if !status.is_success() {
+        return Err(OrderError::Http(status, body));
+    }
+    let added =
+        from_str::<OrderAddedMessage>(&body).map_err(|err| OrderError::Unparsable(body, err))?;
+    debug!(order_id = %added.id, filled = ?added.filled, "Order added");
+    Ok(added)
+}
+
+/// An IOC order whose outcome is unknown because its request failed after it may have been sent
+#[derive(Debug)]
+pub struct UnconfirmedOrder {
+    pub product: String,
+    pub side: Side,
+    pub price: Price,
+    /// When the order was handed to its task, only trades seen after this could be its own
+    pub submitted: Instant,
+}
+
+impl UnconfirmedOrder {
+    pub fn new(order: &AddMessage, submitted: Instant) -> Self {
+        UnconfirmedOrder {
+            product: order.product.clone(),
+            side: order.side,
+            price: order.price,
+            submitted,
+        }
+    }
+
+    /// Whether a trade on the same product could have been caused by this order aggressing
+    pub fn traded(&self, trade: &TradeMessage, username: &Username) -> bool {
+        match self.side {
+            Side::Buy => {
+                trade.trade_type == TradeType::BuyAggressor
+                    && trade.buyer == *username
+                    && trade.price <= self.price
+            }
+            Side::Sell => {
+                trade.trade_type == TradeType::SellAggressor
+                    && trade.seller == *username
+                    && trade.price >= self.price
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExecutionEvent {
+    /// Orders from a strategy that must either all be sent or none at all
+    Intent {
+        strategy: String,
+        orders: Vec<AddMessage>,
+    },
+    /// A trade applied by the feed task
+    Trade(Arc<TradeMessage>),
+    /// One of our orders has been added to a book on the feed
+    Added(String),
+    /// A book stopped trading
+    Halt(String),
+}
+
+/// Strategy that sent the order, the order, when it was submitted and its outcome
+type OrderResponse = (
+    String,
+    AddMessage,
+    Instant,
+    Result<OrderAddedMessage, OrderError>,
+);
+
+/// Sends order intents and keeps books disabled until their outcome is known on the feed
+pub struct Execution {
+    pub username: Username,
+    pub password: String,
+    pub client: reqwest::Client,
+    pub config: ExecutionConfig,
+    pub watchdog: WatchdogConfig,
+    pub latency: Arc<Mutex<OrderLatency>>,
+    pub book_states: Arc<Mutex<BookStates>>,
+    pub attribution: Arc<Mutex<Attribution>>,
+    pub metrics: Arc<Mutex<Metrics>>,
+    /// Where to acknowledge the orders of each strategy
+    pub strategies: HashMap<String, UnboundedSender<StrategyEvent>>,
+    pending_fills: PendingFills,
+    /// Number of orders sent without a response yet, keyed by product
+    in_flight: HashMap<String, usize>,
+    /// Orders whose request failed after possibly reaching the exchange, keyed by submission number
+    unconfirmed_orders: HashMap<u64, UnconfirmedOrder>,
+    /// Number given to the next order submitted
+    next_submission: u64,
+    /// Our own aggressive trades seen while orders are in flight, keyed by product
+    recent_trades: HashMap<String, Vec<(Instant, Arc<TradeMessage>)>>,
+    /// Aggressor order IDs whose trades already confirmed an unconfirmed order, keyed by product,
+    /// so the other trades of an order sweeping several levels do not confirm unrelated orders
+    confirmed_aggressors: HashMap<String, HashSet<String>>,
+    /// Handed to the order tasks to report back their response
+    responses: UnboundedSender<OrderResponse>,
+    response_receiver: Option<UnboundedReceiver<OrderResponse>>,
+}
+
+impl Execution {
+    pub fn new(
+        trader: &AutoTrader,
+        strategies: HashMap<String, UnboundedSender<StrategyEvent>>,
+    ) -> Self {
+        let (responses, response_receiver) = unbounded_channel();
+        Execution {
+            username: trader.username.clone(),
+            password: trader.password.clone(),
+            client: trader.client.clone(),
+            config: trader.execution.clone(),
+            watchdog: trader.watchdog.clone(),
+            latency: trader.latency.clone(),
+            book_states: trader.book_states.clone(),
+            attribution: trader.attribution.clone(),
+            metrics: trader.metrics.clone(),
+            strategies,
+            pending_fills: PendingFills::default(),
+            in_flight: HashMap::new(),
+            unconfirmed_orders: HashMap::new(),
+            next_submission: 0,
+            recent_trades: HashMap::new(),
+            confirmed_aggressors: HashMap::new(),
+            responses,
+            response_receiver: Some(response_receiver),
+        }
+    }
+
+    /// Runs until every sender of execution events has been dropped
+    pub async fn run(mut self, mut events: UnboundedReceiver<ExecutionEvent>) {
+        let mut responses = self
+            .response_receiver
+            .take()
+            .expect("Execution can only run once");
+        let mut watchdog = interval(self.watchdog.interval);
+        watchdog.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            select! {
+                event = events.recv() => {
+                    let Some(event) = event else {
+                        break;
+                    };
+                    self.on_event(event);
+                }
+                Some((strategy, order, submitted, result)) = responses.recv() => {
+                    self.on_response(&strategy, order, submitted, result);
+                }
+                _ = watchdog.tick() => {
+                    self.on_watchdog();
+                }
+            }
+        }
+    }
+
+    fn on_event(&mut self, event: ExecutionEvent) {
+        match event {
+            ExecutionEvent::Intent { strategy, orders } => self.on_intent(strategy, orders),
+            ExecutionEvent::Trade(trade) => {
+                let ours = match trade.trade_type {
+                    TradeType::BuyAggressor => trade.buyer == self.username,
+                    TradeType::SellAggressor => trade.seller == self.username,
+                    TradeType::BrokerTrade => false,
+                };
+                self.attribution.lock().unwrap().trade(trade.clone(), ours);
+                if ours {
+                    self.latency
+                        .lock()
+                        .unwrap()
+                        .record_feed(&trade.aggressor_order);
+                }
+                if self.pending_fills.trade(&trade, &self.username) {
+                    self.book_states
+                        .lock()
+                        .unwrap()
+                        .enable(&trade.product, DisableReason::AwaitingTrade);
+                }
+                let confirmed_before = self
+                    .confirmed_aggressors
+                    .get(&trade.product)
+                    .is_some_and(|confirmed| confirmed.contains(&trade.aggressor_order));
+                if !ours || confirmed_before {
+                    return;
+                }
+                // Oldest unconfirmed order this trade could belong to
+                let confirmed = self
+                    .unconfirmed_orders
+                    .iter()
+                    .filter(|(_, order)| {
+                        order.product == trade.product && order.traded(&trade, &self.username)
+                    })
+                    .min_by_key(|(_, order)| order.submitted)
+                    .map(|(&submission, _)| submission);
+                if let Some(submission) = confirmed {
+                    info!(
+                        product = %trade.product,
+                        sequence = trade.sequence,
+                        order_id = %trade.aggressor_order,
+                        "Unconfirmed order traded",
+                    );
+                    self.confirmed_aggressors
+                        .entry(trade.product.clone())
+                        .or_default()
+                        .insert(trade.aggressor_order.clone());
+                    self.confirm(submission);
+                } else if self.in_flight.contains_key(&trade.product) {
+                    // Keep it in case an order in flight fails without telling whether it traded
+                    self.recent_trades
+                        .entry(trade.product.clone())
+                        .or_default()
+                        .push((Instant::now(), trade));
+                }
+            }
+            ExecutionEvent::Added(id) => {
+                self.latency.lock().unwrap().record_feed(&id);
+            }
+            ExecutionEvent::Halt(product) => {
+                self.book_states.lock().unwrap().remove(&product);
+                self.pending_fills.remove(&product);
+                self.pending_fills.clear_seen(&product);
+                self.in_flight.remove(&product);
+                self.recent_trades.remove(&product);
+                self.confirmed_aggressors.remove(&product);
+                self.unconfirmed_orders
+                    .retain(|_, order| order.product != product);
+            }
+        }
+    }
+
+    fn on_intent(&mut self, strategy: String, orders: Vec<AddMessage>) {
+        let mut book_states = self.book_states.lock().unwrap();
+        // A book may have been disabled since the strategy looked at it
+        let fired = orders
+            .iter()
+            .all(|order| book_states.is_enabled(&order.product));
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.intent(&strategy, fired);
+        if !fired {
+            return;
+        }
+        metrics.orders_sent += orders.len() as u64;
+        drop(metrics);
+        for order in orders {
+            // Disable the books where an order is about to be sent
+            book_states.disable(
+                &order.product,
+                DisableReason::OrderInFlight,
+                self.config.max_order_duration(),
+            );
+            *self.in_flight.entry(order.product.clone()).or_default() += 1;
+            let submitted = Instant::now();
+            let username = self.username.clone();
+            let password = self.password.clone();
+            let client = self.client.clone();
+            let config = self.config.clone();
+            let latency = self.latency.clone();
+            let responses = self.responses.clone();
+            let strategy = strategy.clone();
+            let span = info_span!(
+                "order",
+                strategy = %strategy,
+                product = %order.product,
+                side = ?order.side,
+                price = ?order.price,
+                volume = ?order.volume,
+            );
+            spawn(
+                async move {
+                    let result =
+                        send_order(&client, &config, &username, &password, &order, &latency).await;
+                    // Responses arriving after the execution task stopped are dropped
+                    let _ = responses.send((strategy, order, submitted, result));
+                }
+                .instrument(span),
+            );
+        }
+    }
+
+    fn on_response(
+        &mut self,
+        strategy: &str,
+        order: AddMessage,
+        submitted: Instant,
+        result: Result<OrderAddedMessage, OrderError>,
+    ) {
+        self.metrics.lock().unwrap().response(match &result {
+            Ok(json) if json.filled > 0 => "filled",
+            Ok(_) => "unfilled",
+            Err(OrderError::Http(..)) => "rejected",
+            Err(_) => "failed",
+        });
+        let mut book_states = self.book_states.lock().unwrap();
+        match &result {
+            Ok(json) => {
+                assert_eq!(
+                    json.resting, 0,
+                    "For IOC orders there should be no resting volume",
+                );
+                if json.filled > 0 {
+                    self.attribution
+                        .lock()
+                        .unwrap()
+                        .order(strategy, json.id.clone());
+                    // Wait for every trade of the filled order to show up on the feed
+                    self.pending_fills
+                        .expect(&order.product, json.id.clone(), json.filled);
+                    if self.pending_fills.is_pending(&order.product) {
+                        book_states.disable(
+                            &order.product,
+                            DisableReason::AwaitingTrade,
+                            self.watchdog.awaiting_trade,
+                        );
+                    }
+                }
+                info!(
+                    strategy,
+                    product = %order.product,
+                    order_id = %json.id,
+                    filled = ?json.filled,
+                    "Order response",
+                );
+            }
+            Err(err) => {
+                warn!(
+                    strategy,
+                    product = %order.product,
+                    ?order,
+                    error = %err,
+                    "Order failed",
+                );
+                if err.may_have_traded() {
+                    let unconfirmed = UnconfirmedOrder::new(&order, submitted);
+                    let recent = self.recent_trades.entry(order.product.clone()).or_default();
+                    // The trades of the order may have shown up on the feed before its error
+                    if let Some(index) = recent.iter().position(|(seen, trade)| {
+                        *seen >= submitted && unconfirmed.traded(trade, &self.username)
+                    }) {
+                        let (_, trade) = recent.remove(index);
+                        recent.retain(|(_, seen)| seen.aggressor_order != trade.aggressor_order);
+                        self.confirmed_aggressors
+                            .entry(order.product.clone())
+                            .or_default()
+                            .insert(trade.aggressor_order.clone());
+                        info!(
+                            product = %trade.product,
+                            sequence = trade.sequence,
+                            order_id = %trade.aggressor_order,
+                            "Unconfirmed order traded",
+                        );
+                    } else {
+                        // Keep the book disabled until the feed tells us whether the order traded,
+                        // restarting the wait of any earlier unconfirmed order on it
+                        book_states.enable(&order.product, DisableReason::Unconfirmed);
+                        book_states.disable(
+                            &order.product,
+                            DisableReason::Unconfirmed,
+                            self.config.confirmation_timeout,
+                        );
+                        self.unconfirmed_orders
+                            .insert(self.next_submission, unconfirmed);
+                        self.next_submission += 1;
+                    }
+                }
+            }
+        }
+        // Reenable book after receiving the response of its last order in flight
+        let in_flight = self.in_flight.entry(order.product.clone()).or_default();
+        *in_flight = in_flight.saturating_sub(1);
+        if *in_flight == 0 {
+            self.in_flight.remove(&order.product);
+            self.pending_fills.clear_seen(&order.product);
+            self.recent_trades.remove(&order.product);
+            book_states.enable(&order.product, DisableReason::OrderInFlight);
+        }
+        drop(book_states);
+        self.forget_confirmed(&order.product);
+        if let Some(events) = self.strategies.get(strategy) {
+            let ack = OrderAck {
+                order,
+                result: result.map_err(|err| err.to_string()),
+            };
+            // The strategy may already have stopped at the end of the session
+            let _ = events.send(StrategyEvent::OrderAck(Arc::new(ack)));
+        }
+    }
+
+    /// An unconfirmed order traded, reenables its book once no other order on it is unconfirmed
+    fn confirm(&mut self, submission: u64) {
+        let Some(order) = self.unconfirmed_orders.remove(&submission) else {
+            return;
+        };
+        if self
+            .unconfirmed_orders
+            .values()
+            .all(|other| other.product != order.product)
+        {
+            self.book_states
+                .lock()
+                .unwrap()
+                .enable(&order.product, DisableReason::Unconfirmed);
+            self.forget_confirmed(&order.product);
+        }
+    }
+
+    /// Forgets which orders confirmed others on a book once none of its orders could still be confirmed
+    fn forget_confirmed(&mut self, product: &str) {
+        if !self.in_flight.contains_key(product)
+            && self
+                .unconfirmed_orders
+                .values()
+                .all(|order| order.product != product)
+        {
+            self.confirmed_aggressors.remove(product);
+        }
+    }
+
+    fn on_watchdog(&mut self) {
+        self.attribution.lock().unwrap().expire(Instant::now());
+        let expired = self.book_states.lock().unwrap().expire(Instant::now());
+        for expired in expired {
+            match expired.reason {
+                DisableReason::AwaitingTrade => {
+                    let missing = self.pending_fills.remove(&expired.product);
+                    warn!(
+                        product = %expired.product,
+                        order_ids = ?missing,
+                        "Gave up waiting for the trades of orders",
+                    );
+                }
+                DisableReason::Unconfirmed => {
+                    // The orders never showed up on the feed so they did not trade
+                    let before = self.unconfirmed_orders.len();
+                    self.unconfirmed_orders
+                        .retain(|_, order| order.product != expired.product);
+                    info!(
+                        product = %expired.product,
+                        orders = before - self.unconfirmed_orders.len(),
+                        "Unconfirmed orders did not trade",
+                    );
+                    self.forget_confirmed(&expired.product);
+                }
+                DisableReason::OrderInFlight => {
+                    error!(
+                        product = %expired.product,
+                        escalations = expired.escalations,
+                        "Order still has no response, disabled books:\n{}",
+                        self.book_states.lock().unwrap(),
+                    );
+                }
+                DisableReason::Crossed => {
+                    error!(
+                        product = %expired.product,
+                        escalations = expired.escalations,
+                        "Book is still crossed",
+                    );
+                }
+                DisableReason::Inconsistent => {
+                    error!(
+                        product = %expired.product,
+                        escalations = expired.escalations,
+                        "Book is still inconsistent with the feed",
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        order::{MessageType, OrderType},
+        types::Volume,
+    };
+
+    fn trade(
+        trade_type: TradeType,
+        price: Price,
+        buyer: Username,
+        seller: Username,
+    ) -> TradeMessage {
+        TradeMessage {
+            product: String::new(),
+            price,
+            volume: Volume(10),
+            buyer,
+            seller,
+            trade_type,
+            passive_order: String::from("1"),
+            passive_order_remaining: Volume(0),
+            aggressor_order: String::from("2"),
+            sequence: 1,
+        }
+    }
+
+    #[test]
+    fn test_unconfirmed_order_traded() {
+        let order = UnconfirmedOrder::new(
+            &AddMessage {
+                message_type: MessageType::Add,
+                product: String::new(),
+                price: Price(1000),
+                side: Side::Buy,
+                volume: Volume(10),
+                order_type: OrderType::Ioc,
+            },
+            Instant::now(),
+        );
+        assert!(order.traded(
+            &trade(
+                TradeType::BuyAggressor,
+                Price(990),
+                Username::KLiang,
+                Username::PRao
+            ),
+            &Username::KLiang,
+        ));
+        assert!(!order.traded(
+            &trade(
+                TradeType::BuyAggressor,
+                Price(1010),
+                Username::KLiang,
+                Username::PRao
+            ),
+            &Username::KLiang,
+        ));
+        assert!(!order.traded(
+            &trade(
+                TradeType::SellAggressor,
+                Price(1000),
+                Username::KLiang,
+                Username::PRao
+            ),
+            &Username::KLiang,
+        ));
+        assert!(!order.traded(
+            &trade(
+                TradeType::BuyAggressor,
+                Price(1000),
+                Username::CChuah,
+                Username::PRao
+            ),
+            &Username::KLiang,
+        ));
+    }
+
+    #[test]
+    fn test_may_have_traded() {
+        assert!(OrderError::Http(StatusCode::BAD_GATEWAY, String::new()).may_have_traded());
+        assert!(!OrderError::Http(StatusCode::BAD_REQUEST, String::new()).may_have_traded());
+        assert!(
+            OrderError::Unparsable(String::new(), from_str::<u8>("").unwrap_err())
+                .may_have_traded()
+        );
+    }
+
+    #[test]
+    fn test_response_waits_for_trades() {
+        let trader = AutoTrader::new(Username::KLiang, String::new());
+        let mut execution = Execution::new(&trader, HashMap::new());
+        let order = AddMessage {
+            message_type: MessageType::Add,
+            product: String::new(),
+            price: Price(1000),
+            side: Side::Buy,
+            volume: Volume(20),
+            order_type: OrderType::Ioc,
+        };
+        execution.book_states.lock().unwrap().disable(
+            &order.product,
+            DisableReason::OrderInFlight,
+            Duration::from_secs(1),
+        );
+        execution.on_response(
+            "index_arbitrage",
+            order,
+            Instant::now(),
+            Ok(from_str(
+                r#"{"id":"2","side":"BUY","price":10.0,"filled":20,"resting":0,"owner":"kliang"}"#,
+            )
+            .expect("Failed to parse order added message")),
+        );
+        assert_eq!(
+            execution
+                .book_states
+                .lock()
+                .unwrap()
+                .disabled_books()
+                .iter()
+                .map(|(_, reason, _)| *reason)
+                .collect::<Vec<_>>(),
+            vec![DisableReason::AwaitingTrade],
+        );
+
+        let trade = trade(
+            TradeType::BuyAggressor,
+            Price(1000),
+            Username::KLiang,
+            Username::PRao,
+        );
+        execution.on_event(ExecutionEvent::Trade(Arc::new(trade.clone())));
+        assert!(!execution.book_states.lock().unwrap().is_enabled(""));
+        execution.on_event(ExecutionEvent::Trade(Arc::new(trade)));
+        assert!(execution.book_states.lock().unwrap().is_enabled(""));
+        assert_eq!(
+            execution
+                .attribution
+                .lock()
+                .unwrap()
+                .strategies
+                .get("index_arbitrage")
+                .expect("Has fills")
+                .volume,
+            20,
+        );
+    }
+
+    #[test]
+    fn test_unconfirmed_order_traded_before_error() {
+        let trader = AutoTrader::new(Username::KLiang, String::new());
+        let mut execution = Execution::new(&trader, HashMap::new());
+        let order = AddMessage {
+            message_type: MessageType::Add,
+            product: String::new(),
+            price: Price(1000),
+            side: Side::Buy,
+            volume: Volume(10),
+            order_type: OrderType::Ioc,
+        };
+        let error = || Err(OrderError::Http(StatusCode::BAD_GATEWAY, String::new()));
+        let submitted = Instant::now();
+        execution.in_flight.insert(order.product.clone(), 3);
+
+        // The first order traded before its request failed
+        execution.on_event(ExecutionEvent::Trade(Arc::new(trade(
+            TradeType::BuyAggressor,
+            Price(1000),
+            Username::KLiang,
+            Username::PRao,
+        ))));
+        execution.on_response("index_arbitrage", order.clone(), submitted, error());
+        assert!(execution.unconfirmed_orders.is_empty());
+
+        // Two more orders on the same book fail without having traded yet
+        execution.on_response("index_arbitrage", order.clone(), submitted, error());
+        execution.on_response("index_arbitrage", order, submitted, error());
+        assert_eq!(execution.unconfirmed_orders.len(), 2);
+        assert!(!execution.book_states.lock().unwrap().is_enabled(""));
+
+        let mut trade = trade(
+            TradeType::BuyAggressor,
+            Price(1000),
+            Username::KLiang,
+            Username::PRao,
+        );
+        trade.aggressor_order = String::from("3");
+        execution.on_event(ExecutionEvent::Trade(Arc::new(trade.clone())));
+        assert_eq!(execution.unconfirmed_orders.len(), 1);
+        assert!(!execution.book_states.lock().unwrap().is_enabled(""));
+        trade.aggressor_order = String::from("4");
+        execution.on_event(ExecutionEvent::Trade(Arc::new(trade)));
+        assert!(execution.unconfirmed_orders.is_empty());
+        assert!(execution.book_states.lock().unwrap().is_enabled(""));
+    }
+
+    #[test]
+    fn test_unconfirmed_orders_sweep() {
+        let trader = AutoTrader::new(Username::KLiang, String::new());
+        let mut execution = Execution::new(&trader, HashMap::new());
+        let order = AddMessage {
+            message_type: MessageType::Add,
+            product: String::new(),
+            price: Price(1000),
+            side: Side::Buy,
+            volume: Volume(10),
+            order_type: OrderType::Ioc,
+        };
+        let error = || Err(OrderError::Http(StatusCode::BAD_GATEWAY, String::new()));
+        let submitted = Instant::now();
+        execution.in_flight.insert(order.product.clone(), 2);
+        execution.on_response("index_arbitrage", order.clone(), submitted, error());
+        execution.on_response("index_arbitrage", order, submitted, error());
+        assert_eq!(execution.unconfirmed_orders.len(), 2);
+
+        // One order sweeps two levels, its second trade must not confirm the other order
+        let mut trade = trade(
+            TradeType::BuyAggressor,
+            Price(990),
+            Username::KLiang,
+            Username::PRao,
+        );
+        trade.aggressor_order = String::from("3");
+        execution.on_event(ExecutionEvent::Trade(Arc::new(trade.clone())));
+        trade.price = Price(1000);
+        execution.on_event(ExecutionEvent::Trade(Arc::new(trade)));
+        assert_eq!(execution.unconfirmed_orders.len(), 1);
+        assert!(!execution.book_states.lock().unwrap().is_enabled(""));
+    }
+}
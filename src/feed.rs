@@ -1,14 +1,18 @@
+//! Messages published on the exchange feed, both over the websocket and by `/recover`.
+
 use crate::{
     observations::Station,
     types::{Price, Side, Volume},
     username::Username,
 };
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize};
 
+/// Position of a message on the feed
 pub trait HasSequence {
     fn sequence(&self) -> u32;
 }
 
+/// Any message on the feed, tagged by its `type`
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE", tag = "type")]
 pub enum Message {
@@ -35,6 +39,34 @@ impl HasSequence for Message {
     }
 }
 
+impl Message {
+    /// The `type` the message is tagged with on the feed
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            Message::Future(_) => "FUTURE",
+            Message::Added(_) => "ADDED",
+            Message::Deleted(_) => "DELETED",
+            Message::Trade(_) => "TRADE",
+            Message::Settlement(_) => "SETTLEMENT",
+            Message::Index(_) => "INDEX",
+            Message::TradingHalt(_) => "TRADING_HALT",
+        }
+    }
+
+    /// Product of the book the message applies to, none for messages that leave the books alone
+    pub fn product(&self) -> Option<&str> {
+        match self {
+            Message::Future(future) => Some(&future.product),
+            Message::Added(added) => Some(&added.product),
+            Message::Deleted(deleted) => Some(&deleted.product),
+            Message::Trade(trade) => Some(&trade.product),
+            Message::TradingHalt(halt) => Some(&halt.product),
+            Message::Settlement(_) | Message::Index(_) => None,
+        }
+    }
+}
+
+/// A new product has been listed
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FutureMessage {
@@ -43,9 +75,13 @@ pub struct FutureMessage {
     pub station_name: String,
     pub expiry: String,
     pub halt_time: String,
+    /// Fee per lot charged to each side of a broker trade
+    #[serde(default)]
+    pub broker_fee: Price,
     pub sequence: u32,
 }
 
+/// An order was added, with whatever did not fill on arrival left resting
 #[derive(Debug, Deserialize)]
 pub struct AddedMessage {
     pub product: String,
@@ -58,6 +94,7 @@ pub struct AddedMessage {
     pub sequence: u32,
 }
 
+/// A resting order was deleted
 #[derive(Debug, Deserialize)]
 pub struct DeletedMessage {
     pub product: String,
@@ -66,7 +103,8 @@ pub struct DeletedMessage {
     pub sequence: u32,
 }
 
-#[derive(Debug, Deserialize)]
+/// Two orders traded
+#[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TradeMessage {
     pub product: String,
@@ -81,7 +119,8 @@ pub struct TradeMessage {
     pub sequence: u32,
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
+/// Which side crossed the spread, or neither for a trade arranged by a broker
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum TradeType {
     SellAggressor,
@@ -89,6 +128,7 @@ pub enum TradeType {
     BrokerTrade,
 }
 
+/// A product settled at its final price
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SettlementMessage {
@@ -99,6 +139,7 @@ pub struct SettlementMessage {
     pub sequence: u32,
 }
 
+/// Stations whose observations make up an index
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct IndexMessage {
@@ -120,6 +161,7 @@ where
     Ok(ids.into_iter().map(Station::from).collect())
 }
 
+/// A product stopped trading
 #[derive(Debug, Deserialize)]
 pub struct TradingHaltMessage {
     pub product: String,
@@ -0,0 +1,162 @@
+use crate::{
+    feed::{TradeMessage, TradeType},
+    types::Volume,
+    username::Username,
+};
+use std::collections::HashMap;
+
+/// Filled aggressive orders whose trades have not all shown up on the feed yet
+#[derive(Debug, Default)]
+pub struct PendingFills {
+    /// Remaining volume to be seen on the feed for each aggressor order ID, keyed by product
+    pending: HashMap<String, HashMap<String, Volume>>,
+    /// Volume of our own aggressive trades seen on the feed before the order response arrived, keyed by product
+    seen: HashMap<String, HashMap<String, Volume>>,
+}
+
+impl PendingFills {
+    /// Start waiting for the trades of a filled order
+    pub fn expect(&mut self, product: &str, order_id: String, filled: Volume) {
+        let seen = self
+            .seen
+            .get_mut(product)
+            .and_then(|seen| seen.remove(&order_id))
+            .unwrap_or_default();
+        if seen >= filled {
+            return;
+        }
+        self.pending
+            .entry(product.to_string())
+            .or_default()
+            .insert(order_id, filled - seen);
+    }
+
+    /// Applies a trade from the feed, returns whether the trade completed the last pending order on its product
+    pub fn trade(&mut self, trade: &TradeMessage, username: &Username) -> bool {
+        let Some(orders) = self.pending.get_mut(&trade.product) else {
+            self.record_unexpected(trade, username);
+            return false;
+        };
+        let Some(remaining) = orders.get_mut(&trade.aggressor_order) else {
+            self.record_unexpected(trade, username);
+            return false;
+        };
+        *remaining = remaining.saturating_sub(trade.volume);
+        if *remaining != 0 {
+            return false;
+        }
+        orders.remove(&trade.aggressor_order);
+        if !orders.is_empty() {
+            return false;
+        }
+        self.pending.remove(&trade.product);
+        true
+    }
+
+    fn record_unexpected(&mut self, trade: &TradeMessage, username: &Username) {
+        let aggressor = match trade.trade_type {
+            TradeType::BuyAggressor => &trade.buyer,
+            TradeType::SellAggressor => &trade.seller,
+            TradeType::BrokerTrade => return,
+        };
+        if aggressor == username {
+            *self
+                .seen
+                .entry(trade.product.clone())
+                .or_default()
+                .entry(trade.aggressor_order.clone())
+                .or_default() += trade.volume;
+        }
+    }
+
+    /// Forget the trades seen ahead of responses on a product once no order on it can claim them
+    pub fn clear_seen(&mut self, product: &str) {
+        self.seen.remove(product);
+    }
+
+    pub fn is_pending(&self, product: &str) -> bool {
+        self.pending.contains_key(product)
+    }
+
+    /// Stop waiting for a product, returning the orders and volume that never showed up
+    pub fn remove(&mut self, product: &str) -> HashMap<String, Volume> {
+        self.pending.remove(product).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Price;
+
+    static PRODUCT: &str = "F_SOP_APP0104T0950";
+
+    fn trade(aggressor_order: &str, volume: u32) -> TradeMessage {
+        TradeMessage {
+            product: PRODUCT.to_string(),
+            price: Price(1000),
+            volume: Volume(volume),
+            buyer: Username::KLiang,
+            seller: Username::PRao,
+            trade_type: TradeType::BuyAggressor,
+            passive_order: String::from("0"),
+            passive_order_remaining: Volume(0),
+            aggressor_order: aggressor_order.to_string(),
+            sequence: 1,
+        }
+    }
+
+    #[test]
+    fn test_multiple_orders_on_one_product() {
+        let mut fills = PendingFills::default();
+        fills.expect(PRODUCT, String::from("1"), Volume(10));
+        fills.expect(PRODUCT, String::from("2"), Volume(5));
+        assert!(fills.is_pending(PRODUCT));
+
+        // First order fills across two price levels
+        assert!(!fills.trade(&trade("1", 4), &Username::KLiang));
+        assert!(!fills.trade(&trade("1", 6), &Username::KLiang));
+        assert!(fills.is_pending(PRODUCT));
+
+        assert!(fills.trade(&trade("2", 5), &Username::KLiang));
+        assert!(!fills.is_pending(PRODUCT));
+    }
+
+    #[test]
+    fn test_trades_seen_before_response() {
+        let mut fills = PendingFills::default();
+        assert!(!fills.trade(&trade("1", 4), &Username::KLiang));
+        fills.expect(PRODUCT, String::from("1"), Volume(10));
+        assert!(fills.is_pending(PRODUCT));
+        assert!(fills.trade(&trade("1", 6), &Username::KLiang));
+
+        assert!(!fills.trade(&trade("2", 5), &Username::KLiang));
+        fills.expect(PRODUCT, String::from("2"), Volume(5));
+        assert!(!fills.is_pending(PRODUCT));
+    }
+
+    #[test]
+    fn test_only_our_aggressive_trades_are_seen() {
+        let mut fills = PendingFills::default();
+        // We were the passive side of this trade
+        assert!(!fills.trade(&trade("1", 4), &Username::PRao));
+        assert!(fills.seen.is_empty());
+
+        assert!(!fills.trade(&trade("2", 4), &Username::KLiang));
+        assert!(!fills.seen.is_empty());
+        fills.clear_seen(PRODUCT);
+        assert!(fills.seen.is_empty());
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut fills = PendingFills::default();
+        fills.expect(PRODUCT, String::from("1"), Volume(10));
+        assert!(!fills.trade(&trade("1", 4), &Username::KLiang));
+        assert_eq!(
+            fills.remove(PRODUCT),
+            HashMap::from([(String::from("1"), Volume(6))]),
+        );
+        assert!(!fills.is_pending(PRODUCT));
+    }
+}
@@ -0,0 +1,401 @@
+//! Consistency checks of a book against itself after every feed message.
+
+use crate::{
+    book::Book,
+    types::{Price, Side, Volume},
+    username::Username,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Display,
+};
+use tracing::error;
+
+/// A way in which a book is inconsistent with itself
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    /// The aggregated level does not hold the volume of the orders resting at its price
+    Level {
+        side: Side,
+        price: Price,
+        level: Volume,
+        orders: Volume,
+    },
+    /// A level is left with no volume
+    EmptyLevel { side: Side, price: Price },
+    /// An order is not queued on either side
+    Unqueued { id: String },
+    /// A queue holds an order the book does not have
+    UnknownQueued {
+        side: Side,
+        price: Price,
+        id: String,
+    },
+    /// An order is queued at another price than its own, or queued again after its first queue
+    Misqueued {
+        side: Side,
+        price: Price,
+        id: String,
+    },
+    /// The best bid has been at or above the best ask for too many messages
+    Crossed {
+        bid: Price,
+        ask: Price,
+        messages: u32,
+    },
+    /// Our exposure does not match the volume of our own resting orders
+    Exposure {
+        side: Side,
+        exposure: Volume,
+        orders: Volume,
+    },
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::Level {
+                side,
+                price,
+                level,
+                orders,
+            } => write!(
+                f,
+                "{side:?} level {price:?} has {level:?} but its orders have {orders:?}",
+            ),
+            Violation::EmptyLevel { side, price } => {
+                write!(f, "{side:?} level {price:?} has no volume")
+            }
+            Violation::Unqueued { id } => write!(f, "order {id} is not queued on either side"),
+            Violation::UnknownQueued { side, price, id } => {
+                write!(f, "unknown order {id} is queued at {side:?} {price:?}")
+            }
+            Violation::Misqueued { side, price, id } => {
+                write!(
+                    f,
+                    "order {id} does not belong in the queue at {side:?} {price:?}"
+                )
+            }
+            Violation::Crossed { bid, ask, messages } => {
+                write!(f, "bid {bid:?} crosses ask {ask:?} for {messages} messages")
+            }
+            Violation::Exposure {
+                side,
+                exposure,
+                orders,
+            } => write!(
+                f,
+                "{side:?} exposure is {exposure:?} but our orders have {orders:?}",
+            ),
+        }
+    }
+}
+
+/// Checks of a book that do not depend on its history
+pub fn violations(book: &Book, username: &Username) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let mut queued = HashSet::new();
+    for (side, levels, queues, exposure) in [
+        (
+            Side::Buy,
+            &book.bids,
+            &book.bid_queues,
+            book.position.bid_exposure,
+        ),
+        (
+            Side::Sell,
+            &book.asks,
+            &book.ask_queues,
+            book.position.ask_exposure,
+        ),
+    ] {
+        let mut resting: BTreeMap<Price, Volume> = BTreeMap::new();
+        let mut own = Volume(0);
+        for (&price, id) in queues
+            .iter()
+            .flat_map(|(price, queue)| queue.iter().map(move |id| (price, id)))
+        {
+            if !queued.insert(id) {
+                violations.push(Violation::Misqueued {
+                    side,
+                    price,
+                    id: id.clone(),
+                });
+                continue;
+            }
+            let Some(order) = book.orders.get(id) else {
+                violations.push(Violation::UnknownQueued {
+                    side,
+                    price,
+                    id: id.clone(),
+                });
+                continue;
+            };
+            if order.price != price {
+                violations.push(Violation::Misqueued {
+                    side,
+                    price,
+                    id: id.clone(),
+                });
+            }
+            let level = resting.entry(order.price).or_default();
+            *level = level.saturating_add(order.volume);
+            if order.owner == *username {
+                own = own.saturating_add(order.volume);
+            }
+        }
+        for (&price, &level) in levels.iter() {
+            if level == 0 {
+                violations.push(Violation::EmptyLevel { side, price });
+            }
+        }
+        let prices: BTreeSet<_> = levels.keys().chain(resting.keys()).copied().collect();
+        for price in prices {
+            let level = levels.get(&price).copied().unwrap_or_default();
+            let orders = resting.get(&price).copied().unwrap_or_default();
+            if level != orders {
+                violations.push(Violation::Level {
+                    side,
+                    price,
+                    level,
+                    orders,
+                });
+            }
+        }
+        if exposure != own {
+            violations.push(Violation::Exposure {
+                side,
+                exposure,
+                orders: own,
+            });
+        }
+    }
+    let mut unqueued: Vec<_> = book
+        .orders
+        .keys()
+        .filter(|id| !queued.contains(id))
+        .collect();
+    unqueued.sort();
+    violations.extend(
+        unqueued
+            .into_iter()
+            .map(|id| Violation::Unqueued { id: id.clone() }),
+    );
+    violations
+}
+
+/// Opt-in check of every book after each message applied to it, remembering the first message that broke one
+#[derive(Debug)]
+pub struct InvariantChecker {
+    pub username: Username,
+    /// Messages a book may stay crossed for while an aggressive order is still being matched
+    pub crossed_grace: u32,
+    /// Consecutive messages each crossed book has been crossed for
+    crossed: HashMap<String, u32>,
+    /// Sequence and description of the first message after which a book broke, with what broke
+    pub broken: Option<(u32, String, Vec<Violation>)>,
+}
+
+impl InvariantChecker {
+    pub fn new(username: Username) -> Self {
+        InvariantChecker {
+            username,
+            crossed_grace: 10,
+            crossed: HashMap::new(),
+            broken: None,
+        }
+    }
+
+    /// Checks a book after a message was applied to it, returns whether the book is still consistent
+    pub fn check(&mut self, sequence: u32, message: impl FnOnce() -> String, book: &Book) -> bool {
+        if self.broken.is_some() {
+            // Everything after the first break is likely a consequence of it
+            return false;
+        }
+        let mut violations = violations(book, &self.username);
+        match book.bbo() {
+            (Some(bid), Some(ask)) if bid.price >= ask.price => {
+                let messages = self.crossed.entry(book.product.clone()).or_default();
+                *messages += 1;
+                if *messages > self.crossed_grace {
+                    violations.push(Violation::Crossed {
+                        bid: bid.price,
+                        ask: ask.price,
+                        messages: *messages,
+                    });
+                }
+            }
+            _ => {
+                self.crossed.remove(&book.product);
+            }
+        }
+        if violations.is_empty() {
+            return true;
+        }
+        error!(
+            product = %book.product,
+            sequence,
+            "Book broke:\n{}",
+            violations
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        );
+        self.broken = Some((sequence, message(), violations));
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        feed::{AddedMessage, TradeMessage, TradeType},
+        observations::Station,
+    };
+    use std::collections::VecDeque;
+
+    static PRODUCT: &str = "F_SOP_APP0104T0950";
+
+    fn added(id: &str, side: Side, price: i32, resting: u32, owner: Username) -> AddedMessage {
+        AddedMessage {
+            product: PRODUCT.to_string(),
+            id: id.to_string(),
+            side,
+            price: Price(price),
+            filled: Volume(0),
+            resting: Volume(resting),
+            owner,
+            sequence: 0,
+        }
+    }
+
+    #[test]
+    fn test_violations() {
+        let username = Username::KLiang;
+        let mut book = Book::new(PRODUCT.to_string(), Station::SydOlympicPark, String::new());
+        book.add_order(added("1", Side::Buy, 1000, 10, Username::PRao), &username)
+            .expect("Message applies to the book");
+        book.add_order(added("2", Side::Buy, 1000, 5, Username::KLiang), &username)
+            .expect("Message applies to the book");
+        book.add_order(added("3", Side::Sell, 1100, 7, Username::KLiang), &username)
+            .expect("Message applies to the book");
+        book.trade(
+            TradeMessage {
+                product: PRODUCT.to_string(),
+                price: Price(1000),
+                volume: Volume(4),
+                buyer: Username::PRao,
+                seller: Username::CChuah,
+                trade_type: TradeType::SellAggressor,
+                passive_order: String::from("1"),
+                passive_order_remaining: Volume(6),
+                aggressor_order: String::from("4"),
+                sequence: 0,
+            },
+            &username,
+        )
+        .expect("Message applies to the book");
+        assert_eq!(violations(&book, &username), Vec::new());
+        let consistent = book.clone();
+
+        book.bids.insert(Price(1000), Volume(12));
+        book.asks.insert(Price(1200), Volume(0));
+        book.position.ask_exposure = Volume(3);
+        book.ask_queues.clear();
+        assert_eq!(
+            violations(&book, &username),
+            vec![
+                Violation::Level {
+                    side: Side::Buy,
+                    price: Price(1000),
+                    level: Volume(12),
+                    orders: Volume(11),
+                },
+                Violation::EmptyLevel {
+                    side: Side::Sell,
+                    price: Price(1200),
+                },
+                Violation::Level {
+                    side: Side::Sell,
+                    price: Price(1100),
+                    level: Volume(7),
+                    orders: Volume(0),
+                },
+                Violation::Exposure {
+                    side: Side::Sell,
+                    exposure: Volume(3),
+                    orders: Volume(0),
+                },
+                Violation::Unqueued {
+                    id: String::from("3"),
+                },
+            ],
+        );
+
+        // Queues out of step with the orders they hold
+        let mut book = consistent;
+        book.bid_queues
+            .get_mut(&Price(1000))
+            .expect("Has a bid queue")
+            .push_back(String::from("9"));
+        book.ask_queues.remove(&Price(1100));
+        book.ask_queues
+            .insert(Price(1200), VecDeque::from([String::from("3")]));
+        book.ask_queues
+            .insert(Price(1000), VecDeque::from([String::from("2")]));
+        assert_eq!(
+            violations(&book, &username),
+            vec![
+                Violation::UnknownQueued {
+                    side: Side::Buy,
+                    price: Price(1000),
+                    id: String::from("9"),
+                },
+                Violation::Misqueued {
+                    side: Side::Sell,
+                    price: Price(1000),
+                    id: String::from("2"),
+                },
+                Violation::Misqueued {
+                    side: Side::Sell,
+                    price: Price(1200),
+                    id: String::from("3"),
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn test_crossed_grace() {
+        let username = Username::KLiang;
+        let mut checker = InvariantChecker::new(username.clone());
+        checker.crossed_grace = 1;
+        let mut book = Book::new(PRODUCT.to_string(), Station::SydOlympicPark, String::new());
+        book.add_order(added("1", Side::Sell, 1000, 10, Username::PRao), &username)
+            .expect("Message applies to the book");
+        book.add_order(added("2", Side::Buy, 1000, 5, Username::CChuah), &username)
+            .expect("Message applies to the book");
+        assert!(checker.check(1, || String::from("first"), &book));
+
+        book.add_order(added("3", Side::Buy, 900, 5, Username::CChuah), &username)
+            .expect("Message applies to the book");
+        assert!(!checker.check(2, || String::from("second"), &book));
+        assert_eq!(
+            checker.broken,
+            Some((
+                2,
+                String::from("second"),
+                vec![Violation::Crossed {
+                    bid: Price(1000),
+                    ask: Price(1000),
+                    messages: 2,
+                }],
+            )),
+        );
+        // Only the first break is reported
+        assert!(!checker.check(3, || String::from("third"), &Book::default()));
+        assert_eq!(checker.broken.as_ref().map(|broken| broken.0), Some(2));
+    }
+}
@@ -0,0 +1,273 @@
+//! Timings of orders from submission to their response and to their appearance on the feed.
+
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display},
+    time::{Duration, Instant},
+};
+
+/// Number of power of two microsecond buckets, the last one covers everything above ~16s
+const BUCKETS: usize = 25;
+
+#[derive(Clone, PartialEq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: [0; BUCKETS],
+            count: 0,
+            sum: Duration::ZERO,
+            min: Duration::MAX,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl Histogram {
+    /// Bucket `b` holds latencies above `2^b` and up to `2^(b+1)` microseconds, the first one everything up to 2µs
+    fn bucket(latency: Duration) -> usize {
+        let micros = latency.as_nanos().div_ceil(1000).saturating_sub(1).max(1);
+        ((u128::BITS - micros.leading_zeros()) as usize - 1).min(BUCKETS - 1)
+    }
+
+    /// Inclusive upper bound of a bucket
+    fn bucket_bound(bucket: usize) -> Duration {
+        Duration::from_micros(1 << (bucket + 1))
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        self.buckets[Histogram::bucket(latency)] += 1;
+        self.count += 1;
+        self.sum += latency;
+        self.min = self.min.min(latency);
+        self.max = self.max.max(latency);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// Upper bound of every bucket but the last along with the number of samples up to it, the last bucket is unbounded
+    pub fn cumulative_buckets(&self) -> Vec<(Duration, u64)> {
+        let mut seen = 0;
+        self.buckets[..BUCKETS - 1]
+            .iter()
+            .enumerate()
+            .map(|(bucket, &count)| {
+                seen += count;
+                (Histogram::bucket_bound(bucket), seen)
+            })
+            .collect()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count != 0).then(|| self.sum / self.count as u32)
+    }
+
+    /// Upper bound of the bucket containing the given quantile, clamped to the largest sample
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(Histogram::bucket_bound(bucket).min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+}
+
+impl Debug for Histogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl Display for Histogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.count == 0 {
+            return write!(f, "no samples");
+        }
+        write!(
+            f,
+            "count={} min={:?} mean={:?} p50<={:?} p90<={:?} p99<={:?} max={:?}",
+            self.count,
+            self.min,
+            self.mean().expect("Histogram is not empty"),
+            self.quantile(0.5).expect("Histogram is not empty"),
+            self.quantile(0.9).expect("Histogram is not empty"),
+            self.quantile(0.99).expect("Histogram is not empty"),
+            self.max,
+        )
+    }
+}
+
+/// Round trip timings of orders sent to the exchange
+#[derive(Default, Debug)]
+pub struct OrderLatency {
+    /// Time from submitting an order until the HTTP response is received
+    pub submit_to_response: Histogram,
+    /// Time from submitting an order until it first shows up on the feed
+    pub submit_to_feed: Histogram,
+    /// Number of orders submitted that have not been acknowledged yet
+    submitted: usize,
+    /// Submission time of orders that have been acknowledged over HTTP but not yet seen on the feed
+    awaiting_feed: HashMap<String, Instant>,
+    /// When our orders showed up on the feed before their acknowledgement named them
+    seen_on_feed: HashMap<String, Instant>,
+}
+
+impl OrderLatency {
+    /// An order is about to be sent, it must be acknowledged whatever its outcome
+    pub fn submit(&mut self) {
+        self.submitted += 1;
+    }
+
+    pub fn record_response(&mut self, submitted: Instant) {
+        self.submit_to_response.record(submitted.elapsed());
+    }
+
+    /// The outcome of a submitted order is known, with its ID and whether it filled if it was added
+    pub fn acknowledge(&mut self, submitted: Instant, added: Option<(&str, bool)>) {
+        self.submitted = self.submitted.saturating_sub(1);
+        if let Some((order_id, filled)) = added {
+            if let Some(seen) = self.seen_on_feed.remove(order_id) {
+                self.submit_to_feed
+                    .record(seen.saturating_duration_since(submitted));
+            } else if filled {
+                self.awaiting_feed.insert(order_id.to_string(), submitted);
+            }
+        }
+        if self.submitted == 0 {
+            // Nothing left that could claim them
+            self.seen_on_feed.clear();
+        }
+    }
+
+    /// One of our orders showed up on the feed
+    pub fn record_feed(&mut self, order_id: &str) {
+        if let Some(submitted) = self.awaiting_feed.remove(order_id) {
+            self.submit_to_feed.record(submitted.elapsed());
+        } else if self.submitted != 0 {
+            self.seen_on_feed
+                .entry(order_id.to_string())
+                .or_insert_with(Instant::now);
+        }
+    }
+}
+
+impl Display for OrderLatency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "submit -> response: {}", self.submit_to_response)?;
+        write!(f, "submit -> feed:     {}", self.submit_to_feed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_histogram() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.count, 0);
+        assert_eq!(histogram.mean(), None);
+        assert_eq!(histogram.quantile(0.5), None);
+    }
+
+    #[test]
+    fn test_histogram_quantiles() {
+        let mut histogram = Histogram::default();
+        for micros in [100, 150, 200, 900, 5000] {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count, 5);
+        assert_eq!(histogram.min, Duration::from_micros(100));
+        assert_eq!(histogram.max, Duration::from_micros(5000));
+        assert_eq!(histogram.mean(), Some(Duration::from_micros(1270)));
+        assert_eq!(histogram.quantile(0.0), Some(Duration::from_micros(128)));
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_micros(256)));
+        assert_eq!(histogram.quantile(0.8), Some(Duration::from_micros(1024)));
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_micros(5000)));
+        let buckets = histogram.cumulative_buckets();
+        assert_eq!(buckets.len(), BUCKETS - 1);
+        assert_eq!(buckets[6], (Duration::from_micros(128), 1));
+        assert_eq!(buckets[7], (Duration::from_micros(256), 3));
+        assert_eq!(buckets[12], (Duration::from_micros(8192), 5));
+    }
+
+    #[test]
+    fn test_histogram_bucket_bounds() {
+        let mut histogram = Histogram::default();
+        for latency in [
+            Duration::from_micros(1),
+            Duration::from_micros(2),
+            Duration::from_nanos(2001),
+            Duration::from_micros(128),
+            Duration::from_micros(129),
+        ] {
+            histogram.record(latency);
+        }
+        let buckets = histogram.cumulative_buckets();
+        // A sample on a bound is counted by the bucket it bounds
+        assert_eq!(buckets[0], (Duration::from_micros(2), 2));
+        assert_eq!(buckets[1], (Duration::from_micros(4), 3));
+        assert_eq!(buckets[6], (Duration::from_micros(128), 4));
+        assert_eq!(buckets[7], (Duration::from_micros(256), 5));
+        assert_eq!(histogram.quantile(0.8), Some(Duration::from_micros(128)));
+    }
+
+    #[test]
+    fn test_order_latency_feed_acknowledgement() {
+        let mut latency = OrderLatency::default();
+        let submitted = Instant::now();
+        latency.submit();
+        latency.submit();
+        latency.record_response(submitted);
+        latency.acknowledge(submitted, Some(("1", true)));
+        latency.record_response(submitted);
+        latency.acknowledge(submitted, Some(("3", false)));
+        assert_eq!(latency.submit_to_response.count, 2);
+
+        latency.record_feed("2");
+        assert_eq!(latency.submit_to_feed.count, 0);
+        latency.record_feed("1");
+        latency.record_feed("1");
+        assert_eq!(latency.submit_to_feed.count, 1);
+        assert!(latency.awaiting_feed.is_empty());
+        assert!(latency.seen_on_feed.is_empty());
+    }
+
+    #[test]
+    fn test_order_latency_feed_before_response() {
+        let mut latency = OrderLatency::default();
+        let submitted = Instant::now();
+        latency.submit();
+        latency.submit();
+        // The fill shows up on the feed before the response names the order
+        latency.record_feed("1");
+        latency.record_response(submitted);
+        latency.acknowledge(submitted, Some(("1", true)));
+        assert_eq!(latency.submit_to_feed.count, 1);
+
+        latency.record_feed("2");
+        latency.acknowledge(submitted, None);
+        assert!(latency.awaiting_feed.is_empty());
+        assert!(latency.seen_on_feed.is_empty());
+    }
+}
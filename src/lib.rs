@@ -0,0 +1,26 @@
+//! Trading against the BOM exchange: decoding its feed into order books, sending orders and
+//! finding arbitrage between each index and its underlying futures.
+//!
+//! The [`autotrader::AutoTrader`] ties everything together for the trader binary, while tools
+//! that only need to follow the market can use [`feed::Message`] and [`book::Book`] directly.
+
+pub mod arbitrage;
+pub mod autotrader;
+pub mod book;
+pub mod config;
+pub mod counterparty;
+pub mod execution;
+pub mod feed;
+mod fills;
+pub mod invariants;
+pub mod latency;
+pub mod markout;
+pub mod metrics;
+pub mod observations;
+pub mod order;
+pub mod reconcile;
+pub mod strategy;
+pub mod tape;
+pub mod types;
+pub mod username;
+pub mod watchdog;
@@ -1,19 +1,77 @@
-mod arbitrage;
-mod autotrader;
-mod book;
-mod feed;
-mod observations;
-mod order;
-mod types;
-mod username;
+use bomex::{
+    arbitrage::IndexArbitrage, autotrader::AutoTrader, execution::ExecutionConfig,
+    observations::Station, reconcile::ReconcileConfig, types::Price, username::Username,
+};
+use std::{fs::File, io::BufWriter, time::Duration};
+use tracing_subscriber::EnvFilter;
+
+/// Logs at the levels given per module by `BOMEX_LOG`, such as `info,bomex::execution=debug`, as JSON when `BOMEX_LOG_FORMAT=json`
+fn init_logging() {
+    let filter = EnvFilter::try_from_env("BOMEX_LOG").unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    if std::env::var("BOMEX_LOG_FORMAT").as_deref() == Ok("json") {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    autotrader::AutoTrader::new(
-        username::Username::KLiang,
+    init_logging();
+    let mut trader = AutoTrader::new(
+        Username::KLiang,
         String::from("de7d8b078d63d5d9ad4e9df2f542eca6"),
-    )
-    .startup()
-    .await?;
+    );
+    if let Ok(interval) = std::env::var("BOMEX_RECONCILE_INTERVAL") {
+        trader = trader.with_reconcile_config(ReconcileConfig {
+            interval: Duration::from_secs(interval.parse()?),
+            swap: std::env::var("BOMEX_RECONCILE_SWAP").is_ok(),
+        });
+    }
+    let mut execution = ExecutionConfig::default();
+    if let Ok(timeout) = std::env::var("BOMEX_REQUEST_TIMEOUT_MS") {
+        execution.request_timeout = Duration::from_millis(timeout.parse()?);
+    }
+    if let Ok(timeout) = std::env::var("BOMEX_CONFIRMATION_TIMEOUT_MS") {
+        execution.confirmation_timeout = Duration::from_millis(timeout.parse()?);
+    }
+    trader = trader.with_execution_config(execution);
+    if let Ok(horizons) = std::env::var("BOMEX_MARKOUT_HORIZONS") {
+        // Comma separated seconds such as `1,5,30`
+        let horizons = horizons
+            .split(',')
+            .map(|horizon| -> Result<_, Box<dyn std::error::Error>> {
+                Ok(Duration::try_from_secs_f64(horizon.trim().parse()?)?)
+            })
+            .collect::<Result<_, _>>()?;
+        trader = trader.with_markout_horizons(horizons);
+    }
+    if let Ok(tick_size) = std::env::var("BOMEX_TICK_SIZE") {
+        let tick_size = Price::from_dollars(tick_size.parse()?)?;
+        for station in [
+            Station::SydAirport,
+            Station::SydOlympicPark,
+            Station::CanberraAirport,
+            Station::Index,
+        ] {
+            trader = trader.with_tick_size(station, tick_size);
+        }
+    }
+    if let Ok(addr) = std::env::var("BOMEX_METRICS_ADDR") {
+        trader = trader.with_metrics_endpoint(addr.parse()?);
+    }
+    if let Ok(path) = std::env::var("BOMEX_TAPE") {
+        trader = trader.with_tape_export(BufWriter::new(File::create(path)?));
+    }
+    if std::env::var("BOMEX_CHECK_INVARIANTS").is_ok() {
+        trader = trader.with_invariant_checks();
+    }
+    let mut arbitrage = IndexArbitrage::new(trader.book_states.clone(), &trader.watchdog);
+    if let Ok(path) = std::env::var("BOMEX_ARBITRAGE_CONFIG") {
+        arbitrage = arbitrage.with_config_file(path.into())?;
+    }
+    trader.register(Box::new(arbitrage));
+    trader.startup().await?;
     Ok(())
 }
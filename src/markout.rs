@@ -0,0 +1,351 @@
+//! How the price moved after each of our fills, over configurable horizons.
+
+use crate::{
+    book::Book,
+    feed::{TradeMessage, TradeType},
+    strategy::Attribution,
+    types::{Price, Side, Volume},
+    username::Username,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Fills that are not the aggressive leg of a strategy order
+const PASSIVE: &str = "passive";
+
+/// One of our fills and how the market moved after it
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnFill {
+    pub product: String,
+    pub order_id: String,
+    /// Whether our order was the aggressor
+    pub aggressive: bool,
+    pub side: Side,
+    pub price: Price,
+    pub volume: Volume,
+    /// The trader on the other side
+    pub counterparty: Username,
+    pub time: Instant,
+    /// Profit in dollars marked at the mid once each horizon passed, none if the book had no mid
+    pub markouts: Vec<Option<f64>>,
+    /// Profit in dollars marked at the settlement price
+    pub settlement: Option<f64>,
+}
+
+impl OwnFill {
+    fn markout(&self, price: f64) -> f64 {
+        let edge = price - self.price.0 as f64 / 100.0;
+        match self.side {
+            Side::Buy => edge * self.volume.0 as f64,
+            Side::Sell => -edge * self.volume.0 as f64,
+        }
+    }
+}
+
+/// Marks every fill of ours against the mid at fixed horizons after it and at settlement
+#[derive(Debug)]
+pub struct Markouts {
+    pub username: Username,
+    /// Sorted from shortest to longest
+    pub horizons: Vec<Duration>,
+    pub fills: Vec<OwnFill>,
+    /// Fills before this one have been marked at every horizon
+    unresolved: usize,
+}
+
+impl Markouts {
+    pub const HORIZONS: [Duration; 3] = [
+        Duration::from_secs(1),
+        Duration::from_secs(10),
+        Duration::from_secs(60),
+    ];
+
+    pub fn new(username: Username, mut horizons: Vec<Duration>) -> Self {
+        horizons.sort();
+        Markouts {
+            username,
+            horizons,
+            fills: Vec::new(),
+            unresolved: 0,
+        }
+    }
+
+    pub fn trade(&mut self, trade: &TradeMessage, now: Instant) {
+        let bought = trade.buyer == self.username;
+        let sold = trade.seller == self.username;
+        if bought == sold {
+            // Not ours, or we traded with ourselves
+            return;
+        }
+        let aggressive = match trade.trade_type {
+            TradeType::BuyAggressor => bought,
+            TradeType::SellAggressor => sold,
+            TradeType::BrokerTrade => false,
+        };
+        self.fills.push(OwnFill {
+            product: trade.product.clone(),
+            order_id: if aggressive {
+                trade.aggressor_order.clone()
+            } else {
+                trade.passive_order.clone()
+            },
+            aggressive,
+            side: if bought { Side::Buy } else { Side::Sell },
+            price: trade.price,
+            volume: trade.volume,
+            counterparty: if bought {
+                trade.seller.clone()
+            } else {
+                trade.buyer.clone()
+            },
+            time: now,
+            markouts: Vec::new(),
+            settlement: None,
+        });
+    }
+
+    /// Marks the fills whose horizons are due by now at the mid of their books, which must not have changed since the due time
+    pub fn update(&mut self, now: Instant, books: &HashMap<String, Arc<Book>>) {
+        for fill in self.fills[self.unresolved..].iter_mut() {
+            while let Some(&horizon) = self.horizons.get(fill.markouts.len()) {
+                if now.duration_since(fill.time) < horizon {
+                    break;
+                }
+                let mid = books.get(&fill.product).and_then(|book| book.mid());
+                fill.markouts.push(mid.map(|mid| fill.markout(mid)));
+            }
+        }
+        while self
+            .fills
+            .get(self.unresolved)
+            .is_some_and(|fill| fill.markouts.len() == self.horizons.len())
+        {
+            self.unresolved += 1;
+        }
+    }
+
+    pub fn settle(&mut self, product: &str, price: Price) {
+        for fill in self.fills.iter_mut().filter(|fill| fill.product == product) {
+            fill.settlement = Some(fill.markout(price.0 as f64 / 100.0));
+        }
+    }
+
+    /// How the price moved in favour of each counterparty of our fills by the longest horizon, the opposite of our markout,
+    /// along with the volume it covers
+    pub fn by_counterparty(&self) -> HashMap<Username, (f64, u32)> {
+        let mut totals: HashMap<Username, (f64, u32)> = HashMap::new();
+        let Some(horizon) = self.horizons.len().checked_sub(1) else {
+            return totals;
+        };
+        for fill in self.fills.iter() {
+            if let Some(Some(markout)) = fill.markouts.get(horizon) {
+                let total = totals.entry(fill.counterparty.clone()).or_default();
+                total.0 -= markout;
+                total.1 = total.1.saturating_add(fill.volume.0);
+            }
+        }
+        totals
+    }
+
+    /// Markouts summed by strategy and product
+    pub fn report(&self, attribution: &Attribution) -> MarkoutReport {
+        let mut rows: BTreeMap<(String, String), MarkoutRow> = BTreeMap::new();
+        for fill in self.fills.iter() {
+            let strategy = fill
+                .aggressive
+                .then(|| attribution.strategy(&fill.order_id))
+                .flatten()
+                .unwrap_or(PASSIVE);
+            let row = rows
+                .entry((strategy.to_string(), fill.product.clone()))
+                .or_insert_with(|| MarkoutRow {
+                    horizons: vec![(0.0, 0); self.horizons.len()],
+                    ..Default::default()
+                });
+            row.fills += 1;
+            row.volume += fill.volume.0;
+            for (total, markout) in row.horizons.iter_mut().zip(fill.markouts.iter()) {
+                if let Some(markout) = markout {
+                    total.0 += markout;
+                    total.1 += fill.volume.0;
+                }
+            }
+            if let Some(settlement) = fill.settlement {
+                row.settlement.0 += settlement;
+                row.settlement.1 += fill.volume.0;
+            }
+        }
+        MarkoutReport {
+            horizons: self.horizons.clone(),
+            rows,
+        }
+    }
+}
+
+/// Markouts of the fills of one strategy on one product
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MarkoutRow {
+    pub fills: u32,
+    pub volume: u32,
+    /// Total markout in dollars and the volume it covers at each horizon
+    pub horizons: Vec<(f64, u32)>,
+    pub settlement: (f64, u32),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MarkoutReport {
+    pub horizons: Vec<Duration>,
+    /// Keyed by strategy then product
+    pub rows: BTreeMap<(String, String), MarkoutRow>,
+}
+
+impl Display for MarkoutReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let format = |(total, volume): (f64, u32)| {
+            if volume == 0 {
+                String::from("-")
+            } else {
+                format!("{total:.2} ({:.3}/lot)", total / volume as f64)
+            }
+        };
+        for ((strategy, product), row) in self.rows.iter() {
+            write!(
+                f,
+                "{strategy} {product}: {} fills, {} lots",
+                row.fills, row.volume,
+            )?;
+            for (horizon, &markout) in self.horizons.iter().zip(row.horizons.iter()) {
+                write!(f, ", {horizon:?} {}", format(markout))?;
+            }
+            writeln!(f, ", settlement {}", format(row.settlement))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap as Levels;
+
+    static PRODUCT: &str = "F_SOP_APP0104T0950";
+
+    fn trade(trade_type: TradeType, price: i32, buyer: Username, seller: Username) -> TradeMessage {
+        TradeMessage {
+            product: PRODUCT.to_string(),
+            price: Price(price),
+            volume: Volume(10),
+            buyer,
+            seller,
+            trade_type,
+            passive_order: String::from("1"),
+            passive_order_remaining: Volume(0),
+            aggressor_order: String::from("2"),
+            sequence: 1,
+        }
+    }
+
+    fn books(bid: i32, ask: i32) -> HashMap<String, Arc<Book>> {
+        HashMap::from([(
+            PRODUCT.to_string(),
+            Arc::new(Book {
+                bids: Levels::from([(Price(bid), Volume(1))]),
+                asks: Levels::from([(Price(ask), Volume(1))]),
+                ..Default::default()
+            }),
+        )])
+    }
+
+    #[test]
+    fn test_markouts() {
+        let start = Instant::now();
+        let mut markouts = Markouts::new(
+            Username::KLiang,
+            vec![Duration::from_secs(10), Duration::from_secs(1)],
+        );
+        // Bought aggressively at 10.00 and sold passively at 10.20
+        markouts.trade(
+            &trade(
+                TradeType::BuyAggressor,
+                1000,
+                Username::KLiang,
+                Username::PRao,
+            ),
+            start,
+        );
+        markouts.trade(
+            &trade(
+                TradeType::BuyAggressor,
+                1020,
+                Username::PRao,
+                Username::KLiang,
+            ),
+            start,
+        );
+        markouts.trade(
+            &trade(
+                TradeType::BuyAggressor,
+                1020,
+                Username::PRao,
+                Username::CChuah,
+            ),
+            start,
+        );
+        assert_eq!(markouts.fills.len(), 2);
+
+        markouts.update(start + Duration::from_millis(500), &books(1000, 1010));
+        assert!(markouts.fills.iter().all(|fill| fill.markouts.is_empty()));
+        markouts.update(start + Duration::from_secs(1), &books(1000, 1010));
+        markouts.update(start + Duration::from_secs(10), &books(1010, 1020));
+        markouts.update(start + Duration::from_secs(20), &HashMap::new());
+        markouts.settle(PRODUCT, Price(1100));
+        assert_eq!(markouts.unresolved, 2);
+
+        let mut attribution = Attribution::default();
+        attribution.order("index_arbitrage", String::from("2"));
+        let report = markouts.report(&attribution);
+        assert_eq!(
+            report.horizons,
+            vec![Duration::from_secs(1), Duration::from_secs(10)]
+        );
+        let row = |strategy: &str| {
+            report
+                .rows
+                .get(&(strategy.to_string(), PRODUCT.to_string()))
+                .expect("Has fills")
+                .clone()
+        };
+        let round = |(total, volume): (f64, u32)| ((total * 100.0).round() / 100.0, volume);
+        let arbitrage = row("index_arbitrage");
+        assert_eq!(arbitrage.fills, 1);
+        assert_eq!(
+            arbitrage
+                .horizons
+                .iter()
+                .copied()
+                .map(round)
+                .collect::<Vec<_>>(),
+            vec![(0.5, 10), (1.5, 10)],
+        );
+        assert_eq!(round(arbitrage.settlement), (10.0, 10));
+        let passive = row(PASSIVE);
+        assert_eq!(
+            passive
+                .horizons
+                .iter()
+                .copied()
+                .map(round)
+                .collect::<Vec<_>>(),
+            vec![(1.5, 10), (0.5, 10)],
+        );
+        assert_eq!(round(passive.settlement), (-8.0, 10));
+        // Both fills were against the same trader
+        let counterparties = markouts.by_counterparty();
+        assert_eq!(counterparties.len(), 1);
+        assert_eq!(round(counterparties[&Username::PRao]), (-2.0, 20));
+    }
+}
@@ -0,0 +1,461 @@
+//! Counters and gauges of the trader, exported in the Prometheus text format.
+
+use crate::{
+    book::Book,
+    latency::{Histogram, OrderLatency},
+    strategy::Attribution,
+    types::Volume,
+    watchdog::BookStates,
+};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::{Display, Write},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// What is exported of a single book
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BookGauges {
+    pub position: i32,
+    pub bid_exposure: Volume,
+    pub ask_exposure: Volume,
+    /// Broker fees paid in hundredths of a dollar
+    pub fees: i64,
+    pub locked_count: u32,
+    pub crossed_count: u32,
+}
+
+impl From<&Book> for BookGauges {
+    fn from(book: &Book) -> Self {
+        BookGauges {
+            position: book.position.position,
+            bid_exposure: book.position.bid_exposure,
+            ask_exposure: book.position.ask_exposure,
+            fees: book.fees,
+            locked_count: book.locked_count,
+            crossed_count: book.crossed_count,
+        }
+    }
+}
+
+/// Order intents of a strategy, each one an arb for the index arbitrage
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Intents {
+    pub found: u64,
+    /// Intents whose orders were sent, the others hit a disabled book
+    pub fired: u64,
+}
+
+/// Counters and gauges kept up to date by the feed and execution tasks
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub sequence: u32,
+    /// When the last feed message was applied
+    pub last_message: Option<Instant>,
+    /// Feed messages applied keyed by their type
+    pub messages: BTreeMap<&'static str, u64>,
+    /// Every live book keyed by product
+    pub books: BTreeMap<String, BookGauges>,
+    pub orders_sent: u64,
+    /// Responses to our orders keyed by how they went
+    pub responses: BTreeMap<&'static str, u64>,
+    /// Keyed by strategy
+    pub intents: BTreeMap<String, Intents>,
+}
+
+impl Metrics {
+    pub fn message(&mut self, sequence: u32, message_type: &'static str, now: Instant) {
+        self.sequence = sequence;
+        self.last_message = Some(now);
+        *self.messages.entry(message_type).or_default() += 1;
+    }
+
+    /// Refreshes the gauges of a book, none if it no longer exists
+    pub fn book(&mut self, product: &str, book: Option<&Book>) {
+        match book {
+            Some(book) => {
+                self.books.insert(product.to_string(), book.into());
+            }
+            None => {
+                self.books.remove(product);
+            }
+        }
+    }
+
+    pub fn intent(&mut self, strategy: &str, fired: bool) {
+        let intents = self.intents.entry(strategy.to_string()).or_default();
+        intents.found += 1;
+        intents.fired += fired as u64;
+    }
+
+    pub fn response(&mut self, outcome: &'static str) {
+        *self.responses.entry(outcome).or_default() += 1;
+    }
+}
+
+/// Metrics in the Prometheus text exposition format
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.0, "# HELP {name} {help}").expect("Writing to a string cannot fail");
+        writeln!(self.0, "# TYPE {name} {kind}").expect("Writing to a string cannot fail");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<_> = labels
+                .iter()
+                .map(|(label, value)| {
+                    let value = value
+                        .replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('\n', "\\n");
+                    format!("{label}=\"{value}\"")
+                })
+                .collect();
+            write!(self.0, "{{{}}}", labels.join(",")).expect("Writing to a string cannot fail");
+        }
+        writeln!(self.0, " {value}").expect("Writing to a string cannot fail");
+    }
+
+    fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        for (bound, count) in histogram.cumulative_buckets() {
+            let bound = bound.as_secs_f64().to_string();
+            let labels: Vec<_> = labels.iter().copied().chain([("le", &*bound)]).collect();
+            self.sample(&format!("{name}_bucket"), &labels, count);
+        }
+        let labels_inf: Vec<_> = labels.iter().copied().chain([("le", "+Inf")]).collect();
+        self.sample(&format!("{name}_bucket"), &labels_inf, histogram.count());
+        self.sample(
+            &format!("{name}_sum"),
+            labels,
+            histogram.sum().as_secs_f64(),
+        );
+        self.sample(&format!("{name}_count"), labels, histogram.count());
+    }
+}
+
+fn render_metrics(exposition: &mut Exposition, metrics: &Metrics, now: Instant) {
+    exposition.family(
+        "bomex_feed_sequence",
+        "gauge",
+        "Sequence number of the last feed message applied",
+    );
+    exposition.sample("bomex_feed_sequence", &[], metrics.sequence);
+    exposition.family(
+        "bomex_feed_lag_seconds",
+        "gauge",
+        "Time since the last feed message was applied",
+    );
+    if let Some(last_message) = metrics.last_message {
+        exposition.sample(
+            "bomex_feed_lag_seconds",
+            &[],
+            now.saturating_duration_since(last_message).as_secs_f64(),
+        );
+    }
+    exposition.family(
+        "bomex_feed_messages_total",
+        "counter",
+        "Feed messages applied by type",
+    );
+    for (message_type, count) in metrics.messages.iter() {
+        exposition.sample(
+            "bomex_feed_messages_total",
+            &[("type", message_type)],
+            count,
+        );
+    }
+
+    exposition.family("bomex_position", "gauge", "Traded position per product");
+    for (product, book) in metrics.books.iter() {
+        exposition.sample("bomex_position", &[("product", product)], book.position);
+    }
+    exposition.family(
+        "bomex_exposure",
+        "gauge",
+        "Volume of our resting orders per product and side",
+    );
+    for (product, book) in metrics.books.iter() {
+        for (side, exposure) in [("bid", book.bid_exposure), ("ask", book.ask_exposure)] {
+            exposition.sample(
+                "bomex_exposure",
+                &[("product", product), ("side", side)],
+                exposure.0,
+            );
+        }
+    }
+    exposition.family(
+        "bomex_broker_fees_dollars",
+        "gauge",
+        "Broker fees paid per product",
+    );
+    for (product, book) in metrics.books.iter() {
+        exposition.sample(
+            "bomex_broker_fees_dollars",
+            &[("product", product)],
+            book.fees as f64 / 100.0,
+        );
+    }
+    exposition.family(
+        "bomex_book_crosses_total",
+        "counter",
+        "Times each book became locked or crossed",
+    );
+    for (product, book) in metrics.books.iter() {
+        for (cross, count) in [
+            ("locked", book.locked_count),
+            ("crossed", book.crossed_count),
+        ] {
+            exposition.sample(
+                "bomex_book_crosses_total",
+                &[("product", product), ("cross", cross)],
+                count,
+            );
+        }
+    }
+
+    exposition.family(
+        "bomex_orders_sent_total",
+        "counter",
+        "Orders sent to the exchange",
+    );
+    exposition.sample("bomex_orders_sent_total", &[], metrics.orders_sent);
+    exposition.family(
+        "bomex_order_responses_total",
+        "counter",
+        "Responses to our orders by outcome",
+    );
+    for (outcome, count) in metrics.responses.iter() {
+        exposition.sample(
+            "bomex_order_responses_total",
+            &[("outcome", outcome)],
+            count,
+        );
+    }
+    exposition.family(
+        "bomex_intents_found_total",
+        "counter",
+        "Order intents found by each strategy, arbs for the index arbitrage",
+    );
+    for (strategy, intents) in metrics.intents.iter() {
+        exposition.sample(
+            "bomex_intents_found_total",
+            &[("strategy", strategy)],
+            intents.found,
+        );
+    }
+    exposition.family(
+        "bomex_intents_fired_total",
+        "counter",
+        "Order intents whose orders were sent",
+    );
+    for (strategy, intents) in metrics.intents.iter() {
+        exposition.sample(
+            "bomex_intents_fired_total",
+            &[("strategy", strategy)],
+            intents.fired,
+        );
+    }
+}
+
+fn render_book_states(exposition: &mut Exposition, products: &[String], book_states: &BookStates) {
+    let enabled = products
+        .iter()
+        .filter(|product| book_states.is_enabled(product))
+        .count();
+    let mut disabled: BTreeMap<String, u64> = BTreeMap::new();
+    for (_, reason, _) in book_states.disabled_books() {
+        *disabled.entry(format!("{reason:?}")).or_default() += 1;
+    }
+    exposition.family(
+        "bomex_books",
+        "gauge",
+        "Live books that may trade, and disabled books by reason",
+    );
+    exposition.sample("bomex_books", &[("state", "Enabled")], enabled);
+    for (reason, count) in disabled.iter() {
+        exposition.sample("bomex_books", &[("state", reason)], count);
+    }
+}
+
+fn render_pnl(exposition: &mut Exposition, attribution: &Attribution) {
+    exposition.family(
+        "bomex_pnl_dollars",
+        "gauge",
+        "PnL of each strategy marked at the last traded prices",
+    );
+    let mut strategies: Vec<_> = attribution.strategies.iter().collect();
+    strategies.sort_by_key(|(name, _)| *name);
+    for (name, pnl) in strategies {
+        exposition.sample(
+            "bomex_pnl_dollars",
+            &[("strategy", name)],
+            pnl.pnl(&attribution.marks),
+        );
+    }
+}
+
+fn render_latency(exposition: &mut Exposition, latency: &OrderLatency) {
+    exposition.family(
+        "bomex_order_latency_seconds",
+        "histogram",
+        "Time from submitting an order until its response or until it shows up on the feed",
+    );
+    exposition.histogram(
+        "bomex_order_latency_seconds",
+        &[("stage", "response")],
+        &latency.submit_to_response,
+    );
+    exposition.histogram(
+        "bomex_order_latency_seconds",
+        &[("stage", "feed")],
+        &latency.submit_to_feed,
+    );
+}
+
+/// Everything the metrics endpoint reads, shared with the trader
+#[derive(Clone)]
+pub struct Exporter {
+    pub metrics: Arc<Mutex<Metrics>>,
+    pub book_states: Arc<Mutex<BookStates>>,
+    pub attribution: Arc<Mutex<Attribution>>,
+    pub latency: Arc<Mutex<OrderLatency>>,
+}
+
+impl Exporter {
+    /// Takes one lock at a time, the execution task locks the book states before the metrics
+    pub fn render(&self, now: Instant) -> String {
+        let mut exposition = Exposition::default();
+        let metrics = self.metrics.lock().unwrap();
+        render_metrics(&mut exposition, &metrics, now);
+        let products: Vec<_> = metrics.books.keys().cloned().collect();
+        drop(metrics);
+        render_book_states(
+            &mut exposition,
+            &products,
+            &self.book_states.lock().unwrap(),
+        );
+        render_pnl(&mut exposition, &self.attribution.lock().unwrap());
+        render_latency(&mut exposition, &self.latency.lock().unwrap());
+        exposition.0
+    }
+
+    fn respond(&self, request: &Request<Body>) -> Response<Body> {
+        if request.method() != Method::GET || request.uri().path() != "/metrics" {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return response;
+        }
+        let mut response = Response::new(Body::from(self.render(Instant::now())));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            "text/plain; version=0.0.4"
+                .parse()
+                .expect("Valid content type"),
+        );
+        response
+    }
+
+    /// Serves `/metrics` until the task is aborted
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), hyper::Error> {
+        let make_service = make_service_fn(move |_| {
+            let exporter = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = exporter.respond(&request);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        Server::try_bind(&addr)?.serve(make_service).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        observations::Station,
+        strategy::StrategyPnl,
+        types::{Price, Side},
+        watchdog::DisableReason,
+    };
+    use std::{collections::HashMap, time::Duration};
+
+    static PRODUCT: &str = "F_SOP_APP0104T0950";
+
+    #[test]
+    fn test_render() {
+        let start = Instant::now();
+        let mut metrics = Metrics::default();
+        metrics.message(7, "ADDED", start);
+        metrics.message(8, "ADDED", start);
+        let mut book = Book::new(PRODUCT.to_string(), Station::SydOlympicPark, String::new());
+        book.position.position = -3;
+        book.position.bid_exposure = Volume(5);
+        metrics.book(PRODUCT, Some(&book));
+        metrics.book("halted", Some(&book));
+        metrics.book("halted", None);
+        metrics.intent("index_arbitrage", true);
+        metrics.intent("index_arbitrage", false);
+        metrics.orders_sent = 4;
+        metrics.response("filled");
+
+        let mut book_states = BookStates::default();
+        book_states.disable(PRODUCT, DisableReason::Crossed, Duration::from_secs(1));
+        let mut attribution = Attribution::default();
+        let mut pnl = StrategyPnl::default();
+        pnl.fill(PRODUCT, Side::Buy, Price(1000), Volume(2));
+        attribution
+            .strategies
+            .insert(String::from("index_arbitrage"), pnl);
+        attribution.marks = HashMap::from([(PRODUCT.to_string(), Price(1050))]);
+        let mut latency = OrderLatency::default();
+        latency.submit_to_response.record(Duration::from_micros(3));
+
+        let exporter = Exporter {
+            metrics: Arc::new(Mutex::new(metrics)),
+            book_states: Arc::new(Mutex::new(book_states)),
+            attribution: Arc::new(Mutex::new(attribution)),
+            latency: Arc::new(Mutex::new(latency)),
+        };
+        let rendered = exporter.render(start + Duration::from_millis(1500));
+        for line in [
+            "# TYPE bomex_feed_sequence gauge",
+            "bomex_feed_sequence 8",
+            "bomex_feed_lag_seconds 1.5",
+            "bomex_feed_messages_total{type=\"ADDED\"} 2",
+            "bomex_position{product=\"F_SOP_APP0104T0950\"} -3",
+            "bomex_exposure{product=\"F_SOP_APP0104T0950\",side=\"bid\"} 5",
+            "bomex_books{state=\"Enabled\"} 0",
+            "bomex_books{state=\"Crossed\"} 1",
+            "bomex_orders_sent_total 4",
+            "bomex_order_responses_total{outcome=\"filled\"} 1",
+            "bomex_intents_found_total{strategy=\"index_arbitrage\"} 2",
+            "bomex_intents_fired_total{strategy=\"index_arbitrage\"} 1",
+            "bomex_pnl_dollars{strategy=\"index_arbitrage\"} 1",
+            "bomex_order_latency_seconds_bucket{stage=\"response\",le=\"0.000002\"} 0",
+            "bomex_order_latency_seconds_bucket{stage=\"response\",le=\"0.000004\"} 1",
+            "bomex_order_latency_seconds_bucket{stage=\"response\",le=\"+Inf\"} 1",
+            "bomex_order_latency_seconds_count{stage=\"feed\"} 0",
+        ] {
+            assert!(
+                rendered.lines().any(|rendered| rendered == line),
+                "{line} missing from:\n{rendered}",
+            );
+        }
+        assert!(!rendered.contains("halted"));
+    }
+}
@@ -1,15 +1,21 @@
-use crate::{
-    autotrader::{AutoTrader, ConstantPorts},
-    url,
-};
+//! Weather observations of the stations the products settle on.
+
+use crate::{strategy::StrategyEvent, url};
 use serde::{de::Error, Deserialize, Deserializer};
 use serde_json::Value;
 use std::{
     cmp::Ordering,
     collections::{BTreeSet, HashMap},
-    sync::{Arc, Mutex},
+    sync::Arc,
+    time::Duration,
 };
+use tokio::{sync::mpsc::UnboundedSender, time::sleep};
+use tracing::warn;
+
+/// How often the latest observations are requested
+const OBSERVATION_INTERVAL: Duration = Duration::from_secs(1);
 
+/// One reading of a station
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Observation {
@@ -44,38 +50,50 @@ impl PartialEq for Observation {
 
 impl Eq for Observation {}
 
-#[allow(dead_code)]
-pub fn poll_observations() {
-    let observations = Arc::new(std::sync::Mutex::new(HashMap::new()));
-    let observations_clone = observations.clone();
-    tokio::spawn(async move {
-        loop {
-            let result = get_latest_observations(observations_clone.clone()).await;
-            if let Err(err) = result {
-                dbg!(err);
+/// Polls the latest observations until aborted, publishing every new one to the strategies
+pub async fn poll_observations(strategies: Vec<UnboundedSender<StrategyEvent>>) {
+    let mut observations = HashMap::new();
+    loop {
+        match get_latest_observations(&mut observations).await {
+            Ok(new_observations) => {
+                for observation in new_observations {
+                    for strategy in strategies.iter() {
+                        // Strategies only stop at the end of the session
+                        let _ = strategy.send(StrategyEvent::Observation(observation.clone()));
+                    }
+                }
+            }
+            Err(err) => {
+                warn!(error = %err, "Failed to get the latest observations");
             }
         }
-    });
-    println!("{observations:#?}");
+        sleep(OBSERVATION_INTERVAL).await;
+    }
 }
 
+/// Returns the observations that were not seen before
 async fn get_latest_observations(
-    observations: Arc<Mutex<HashMap<Station, BTreeSet<Observation>>>>,
-) -> Result<(), reqwest::Error> {
-    let response: Vec<Observation> = reqwest::get(url!(AutoTrader::OBSERVATION_PORT, "current"))
+    observations: &mut HashMap<Station, BTreeSet<Arc<Observation>>>,
+) -> Result<Vec<Arc<Observation>>, reqwest::Error> {
+    let response: Vec<Observation> = reqwest::get(url!(OBSERVATION_PORT, "current"))
         .await?
         .json()
         .await?;
+    let mut new_observations = Vec::new();
     for observation in response {
-        let mut observations = observations.lock().unwrap();
-        let existing_observations = observations.entry(observation.station).or_default();
-        if !existing_observations.contains(&observation) {
-            existing_observations.insert(observation);
+        let observation = Arc::new(observation);
+        if observations
+            .entry(observation.station)
+            .or_default()
+            .insert(observation.clone())
+        {
+            new_observations.push(observation);
         }
     }
-    Ok(())
+    Ok(new_observations)
 }
 
+/// A weather station, identified on the feed by its ID
 #[derive(Default, Debug, Clone, Copy, Eq, Hash, PartialEq)]
 pub enum Station {
     SydAirport = 0,
@@ -1,10 +1,13 @@
+//! Messages sent to and received from the execution endpoint.
+
 use crate::{
     types::{Price, Side, Volume},
     username::Username,
 };
 use serde::{Deserialize, Serialize};
 
-#[derive(PartialEq, Debug, Serialize)]
+/// An order to add to a book
+#[derive(PartialEq, Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AddMessage {
     #[serde(rename = "type")]
@@ -16,7 +19,8 @@ pub struct AddMessage {
     pub order_type: OrderType,
 }
 
-#[derive(Debug, Deserialize)]
+/// Response to an order that reached the exchange
+#[derive(Debug, Clone, Deserialize)]
 pub struct OrderAddedMessage {
     pub id: String,
     #[allow(dead_code)]
@@ -29,6 +33,7 @@ pub struct OrderAddedMessage {
     owner: Username,
 }
 
+/// Request to delete one of our resting orders
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DeleteMessage {
@@ -38,6 +43,7 @@ pub struct DeleteMessage {
     pub id: String,
 }
 
+/// Request to delete all of our resting orders on a book
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BulkDeleteMessage {
@@ -46,7 +52,7 @@ pub struct BulkDeleteMessage {
     pub product: String,
 }
 
-#[derive(PartialEq, Debug, Serialize)]
+#[derive(PartialEq, Debug, Clone, Serialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum MessageType {
     Add,
@@ -56,7 +62,7 @@ pub enum MessageType {
     BulkDelete,
 }
 
-#[derive(PartialEq, Debug, Serialize)]
+#[derive(PartialEq, Debug, Clone, Serialize)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum OrderType {
     #[allow(dead_code)]
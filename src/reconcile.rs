@@ -0,0 +1,248 @@
+//! Checks of the live books against fresh `/recover` snapshots.
+
+use crate::{book::Book, feed::Message};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Debug,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    spawn,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::{interval, Interval, MissedTickBehavior},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconcileConfig {
+    /// How often a fresh snapshot is fetched from `/recover`
+    pub interval: Duration,
+    /// Replace the live books that differ with the recovered ones
+    pub swap: bool,
+}
+
+impl Default for ReconcileConfig {
+    fn default() -> Self {
+        ReconcileConfig {
+            interval: Duration::from_secs(60),
+            swap: false,
+        }
+    }
+}
+
+pub enum ReconcileEvent {
+    /// Time to request another snapshot
+    Fetch,
+    Snapshot(Result<Vec<Message>, String>),
+}
+
+/// Periodically fetches `/recover` in the background for the feed task to check its books against
+pub struct Reconciler {
+    pub config: ReconcileConfig,
+    interval: Interval,
+    sender: UnboundedSender<Result<Vec<Message>, String>>,
+    snapshots: UnboundedReceiver<Result<Vec<Message>, String>>,
+    /// Raw feed messages applied since the snapshot in flight was requested, none if no snapshot is in flight
+    buffered: Option<Vec<Vec<u8>>>,
+}
+
+impl Reconciler {
+    pub fn new(config: ReconcileConfig) -> Self {
+        let mut interval = interval(config.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The books were just recovered, so skip the immediate first tick
+        interval.reset();
+        let (sender, snapshots) = unbounded_channel();
+        Reconciler {
+            config,
+            interval,
+            sender,
+            snapshots,
+            buffered: None,
+        }
+    }
+
+    pub async fn next(&mut self) -> ReconcileEvent {
+        if self.buffered.is_some() {
+            let snapshot = self.snapshots.recv().await;
+            ReconcileEvent::Snapshot(snapshot.expect("Reconciler holds a sender"))
+        } else {
+            self.interval.tick().await;
+            ReconcileEvent::Fetch
+        }
+    }
+
+    /// Requests a snapshot, buffering the feed until it arrives
+    pub fn fetch(&mut self, url: String) {
+        self.buffered = Some(Vec::new());
+        let sender = self.sender.clone();
+        spawn(async move {
+            let snapshot = async { reqwest::get(url).await?.json::<Vec<Message>>().await }.await;
+            // The feed task may have finished in the meantime
+            let _ = sender.send(snapshot.map_err(|err| err.to_string()));
+        });
+    }
+
+    /// Keeps a feed message that was applied while a snapshot is in flight
+    pub fn record(&mut self, message: &[u8]) {
+        if let Some(buffered) = self.buffered.as_mut() {
+            buffered.push(message.to_vec());
+        }
+    }
+
+    /// Feed messages applied since the snapshot was requested, while still buffering the ones that follow
+    pub fn drain_buffered(&mut self) -> Vec<Vec<u8>> {
+        self.buffered
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Feed messages applied since the last drain, after which the next snapshot can be requested
+    pub fn take_buffered(&mut self) -> Vec<Vec<u8>> {
+        self.buffered.take().unwrap_or_default()
+    }
+}
+
+/// The next event of the reconciler, if reconciling at all
+pub async fn next_event(reconciler: &mut Option<Reconciler>) -> ReconcileEvent {
+    match reconciler {
+        Some(reconciler) => reconciler.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn diff_entries<'a, K: Debug + Ord + 'a, V: Debug + PartialEq + 'a>(
+    diffs: &mut Vec<String>,
+    product: &str,
+    what: &str,
+    live: impl Iterator<Item = (&'a K, &'a V)>,
+    recovered: impl Iterator<Item = (&'a K, &'a V)>,
+) {
+    let live: BTreeMap<_, _> = live.collect();
+    let recovered: BTreeMap<_, _> = recovered.collect();
+    let keys: BTreeSet<_> = live.keys().chain(recovered.keys()).collect();
+    for key in keys {
+        let (live, recovered) = (live.get(key), recovered.get(key));
+        if live != recovered {
+            diffs.push(format!(
+                "{product}: {what} {key:?} is {live:?} live but {recovered:?} recovered",
+            ));
+        }
+    }
+}
+
+/// One line per level, order or position that differs between the live and recovered books
+pub fn diff(
+    live: &HashMap<String, Arc<Book>>,
+    recovered: &HashMap<String, Arc<Book>>,
+) -> Vec<String> {
+    let products: BTreeSet<_> = live.keys().chain(recovered.keys()).collect();
+    let mut diffs = Vec::new();
+    for product in products {
+        let (live, recovered) = match (live.get(product), recovered.get(product)) {
+            (Some(live), Some(recovered)) => (live, recovered),
+            (live, _) => {
+                diffs.push(format!(
+                    "{product}: only the {} book exists",
+                    if live.is_some() { "live" } else { "recovered" },
+                ));
+                continue;
+            }
+        };
+        if live == recovered {
+            continue;
+        }
+        diff_entries(
+            &mut diffs,
+            product,
+            "bid",
+            live.bids.iter(),
+            recovered.bids.iter(),
+        );
+        diff_entries(
+            &mut diffs,
+            product,
+            "ask",
+            live.asks.iter(),
+            recovered.asks.iter(),
+        );
+        diff_entries(
+            &mut diffs,
+            product,
+            "order",
+            live.orders.iter(),
+            recovered.orders.iter(),
+        );
+        if live.position != recovered.position {
+            diffs.push(format!(
+                "{product}: position is {:?} live but {:?} recovered",
+                live.position, recovered.position,
+            ));
+        }
+        if live.fees != recovered.fees {
+            diffs.push(format!(
+                "{product}: fees are {} live but {} recovered",
+                live.fees, recovered.fees,
+            ));
+        }
+    }
+    diffs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        book::Order,
+        types::{Price, Volume},
+        username::Username,
+    };
+
+    static PRODUCT: &str = "F_SOP_APP0104T0950";
+
+    #[test]
+    fn test_diff() {
+        let book = Book {
+            product: PRODUCT.to_string(),
+            bids: BTreeMap::from([(Price(1000), Volume(10))]),
+            orders: HashMap::from([(
+                String::from("1"),
+                Order {
+                    owner: Username::PRao,
+                    price: Price(1000),
+                    volume: Volume(10),
+                },
+            )]),
+            ..Default::default()
+        };
+        let live = HashMap::from([(PRODUCT.to_string(), Arc::new(book.clone()))]);
+        let mut recovered = HashMap::from([(
+            PRODUCT.to_string(),
+            Arc::new(Book {
+                version: 7,
+                ..book.clone()
+            }),
+        )]);
+        assert_eq!(diff(&live, &recovered), Vec::<String>::new());
+
+        let diverged = Arc::make_mut(recovered.get_mut(PRODUCT).expect("Book exists"));
+        diverged.bids.insert(Price(1000), Volume(4));
+        diverged.orders.get_mut("1").expect("Order exists").volume = Volume(4);
+        diverged.position.position = 6;
+        recovered.insert(String::from("other"), Arc::default());
+        assert_eq!(
+            diff(&live, &recovered),
+            vec![
+                format!("{PRODUCT}: bid 10 is Some(10) live but Some(4) recovered"),
+                format!(
+                    "{PRODUCT}: order \"1\" is Some(Order {{ owner: PRao, price: 10, volume: 10 }}) live but Some(Order {{ owner: PRao, price: 10, volume: 4 }}) recovered"
+                ),
+                format!(
+                    "{PRODUCT}: position is Position {{ bid_exposure: 0, ask_exposure: 0, position: 0 }} live but Position {{ bid_exposure: 0, ask_exposure: 0, position: 6 }} recovered"
+                ),
+                String::from("other: only the recovered book exists"),
+            ],
+        );
+    }
+}
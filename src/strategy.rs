@@ -0,0 +1,433 @@
+//! Strategies and the events they receive, with fills and PnL attributed to each.
+
+use crate::{
+    book::Book,
+    execution::ExecutionEvent,
+    feed::{TradeMessage, TradeType},
+    observations::Observation,
+    order::{AddMessage, OrderAddedMessage},
+    types::{Price, Side, Volume},
+};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    select,
+    sync::{
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        Notify,
+    },
+    time::{interval, MissedTickBehavior},
+};
+use tracing::warn;
+
+/// Orders that are either all sent or none at all
+pub type OrderIntent = Vec<AddMessage>;
+
+/// Events delivered to every strategy task, book snapshots go through its [`BookSlot`] instead
+#[derive(Debug, Clone)]
+pub enum StrategyEvent {
+    /// A book stopped trading
+    Halt(String),
+    Trade(Arc<TradeMessage>),
+    Observation(Arc<Observation>),
+    /// Response to one of this strategy's own orders
+    OrderAck(Arc<OrderAck>),
+}
+
+#[derive(Debug)]
+pub struct OrderAck {
+    pub order: AddMessage,
+    /// The order as added by the exchange or why it failed
+    pub result: Result<OrderAddedMessage, String>,
+}
+
+pub trait Strategy: Send {
+    /// Unique name used to attribute orders, fills and PnL
+    fn name(&self) -> &str;
+
+    fn on_book(&mut self, _book: Arc<Book>) -> Vec<OrderIntent> {
+        Vec::new()
+    }
+
+    fn on_halt(&mut self, _product: &str) {}
+
+    fn on_trade(&mut self, _trade: &TradeMessage) -> Vec<OrderIntent> {
+        Vec::new()
+    }
+
+    fn on_observation(&mut self, _observation: &Observation) -> Vec<OrderIntent> {
+        Vec::new()
+    }
+
+    fn on_order_ack(&mut self, _ack: &OrderAck) -> Vec<OrderIntent> {
+        Vec::new()
+    }
+
+    /// Called periodically even when nothing else happens
+    fn on_timer(&mut self) -> Vec<OrderIntent> {
+        Vec::new()
+    }
+
+    /// How often `on_timer` is called
+    fn timer_interval(&self) -> Duration {
+        Duration::from_millis(250)
+    }
+}
+
+/// Every strategy that runs side by side in one trader
+#[derive(Default)]
+pub struct StrategyRegistry {
+    strategies: Vec<Box<dyn Strategy>>,
+}
+
+impl StrategyRegistry {
+    pub fn register(&mut self, strategy: Box<dyn Strategy>) {
+        assert!(
+            self.strategies
+                .iter()
+                .all(|registered| registered.name() != strategy.name()),
+            "Strategy {} is already registered",
+            strategy.name(),
+        );
+        self.strategies.push(strategy);
+    }
+
+    pub fn take(&mut self) -> Vec<Box<dyn Strategy>> {
+        std::mem::take(&mut self.strategies)
+    }
+}
+
+/// Latest snapshot of every book that changed since a strategy last looked, a snapshot is dropped as soon as a newer one of the same book is published
+#[derive(Debug, Default)]
+pub struct BookSlot {
+    /// In the order the books first changed
+    books: Mutex<Vec<Arc<Book>>>,
+    changed: Notify,
+}
+
+impl BookSlot {
+    pub fn publish(&self, book: Arc<Book>) {
+        let mut books = self.books.lock().unwrap();
+        match books
+            .iter_mut()
+            .find(|pending| pending.product == book.product)
+        {
+            Some(pending) => *pending = book,
+            None => books.push(book),
+        }
+        drop(books);
+        self.changed.notify_one();
+    }
+
+    /// Drops the pending snapshot of a book that stopped trading
+    pub fn remove(&self, product: &str) {
+        self.books
+            .lock()
+            .unwrap()
+            .retain(|pending| pending.product != product);
+    }
+
+    pub fn take(&self) -> Vec<Arc<Book>> {
+        std::mem::take(&mut *self.books.lock().unwrap())
+    }
+}
+
+/// Strategy task, runs until every sender of strategy events has been dropped
+pub async fn run(
+    mut strategy: Box<dyn Strategy>,
+    mut events: UnboundedReceiver<StrategyEvent>,
+    books: Arc<BookSlot>,
+    execution: UnboundedSender<ExecutionEvent>,
+) {
+    let mut timer = interval(strategy.timer_interval());
+    timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        let intents = select! {
+            event = events.recv() => {
+                let Some(event) = event else {
+                    break;
+                };
+                let mut batch = vec![event];
+                // Catch up on everything published while the strategy was busy
+                while let Ok(event) = events.try_recv() {
+                    batch.push(event);
+                }
+                // Books are published before the events that changed them
+                dispatch(strategy.as_mut(), books.take(), batch)
+            }
+            _ = books.changed.notified() => dispatch(strategy.as_mut(), books.take(), Vec::new()),
+            _ = timer.tick() => strategy.on_timer(),
+        };
+        for orders in intents {
+            let intent = ExecutionEvent::Intent {
+                strategy: strategy.name().to_string(),
+                orders,
+            };
+            if execution.send(intent).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Delivers the latest books and then a batch of events
+fn dispatch(
+    strategy: &mut dyn Strategy,
+    books: Vec<Arc<Book>>,
+    batch: Vec<StrategyEvent>,
+) -> Vec<OrderIntent> {
+    let mut intents = Vec::new();
+    for book in books {
+        intents.extend(strategy.on_book(book));
+    }
+    for event in batch {
+        match event {
+            StrategyEvent::Halt(product) => strategy.on_halt(&product),
+            StrategyEvent::Trade(trade) => intents.extend(strategy.on_trade(&trade)),
+            StrategyEvent::Observation(observation) => {
+                intents.extend(strategy.on_observation(&observation))
+            }
+            StrategyEvent::OrderAck(ack) => intents.extend(strategy.on_order_ack(&ack)),
+        }
+    }
+    intents
+}
+
+/// Fills and PnL of a single strategy
+#[derive(Debug, Default)]
+pub struct StrategyPnl {
+    pub positions: HashMap<String, i32>,
+    /// Cash spent and received in hundredths of a dollar
+    pub cash: i64,
+    pub fills: u32,
+    pub volume: u32,
+}
+
+impl StrategyPnl {
+    pub fn fill(&mut self, product: &str, side: Side, price: Price, volume: Volume) {
+        let volume_signed = volume.signed().expect("Fill volume fits a position");
+        let signed = if side == Side::Buy {
+            volume_signed
+        } else {
+            -volume_signed
+        };
+        *self.positions.entry(product.to_string()).or_default() += signed;
+        self.cash -= signed as i64 * price.0 as i64;
+        self.fills += 1;
+        self.volume += volume.0;
+    }
+
+    /// Cash plus open positions marked at the given prices, in dollars
+    pub fn pnl(&self, marks: &HashMap<String, Price>) -> f64 {
+        let open: i64 = self
+            .positions
+            .iter()
+            .map(|(product, &position)| {
+                position as i64 * marks.get(product).map_or(0, |price| price.0 as i64)
+            })
+            .sum();
+        (self.cash + open) as f64 / 100.0
+    }
+}
+
+/// Attributes our aggressive fills on the feed to the strategy whose order caused them
+#[derive(Debug, Default)]
+pub struct Attribution {
+    /// Strategy that sent each filled order
+    orders: HashMap<String, String>,
+    /// Our aggressive trades seen on the feed before the response naming their order, along with when the first one was seen
+    unattributed: HashMap<String, (Instant, Vec<Arc<TradeMessage>>)>,
+    pub strategies: HashMap<String, StrategyPnl>,
+    /// Last traded price of every product used to mark open positions
+    pub marks: HashMap<String, Price>,
+}
+
+impl Attribution {
+    /// How long our trades wait for the response naming their order, well past any request timeout
+    pub const UNATTRIBUTED_TIMEOUT: Duration = Duration::from_secs(60);
+
+    pub fn order(&mut self, strategy: &str, order_id: String) {
+        if let Some((_, trades)) = self.unattributed.remove(&order_id) {
+            for trade in trades {
+                self.fill(strategy, &trade);
+            }
+        }
+        self.orders.insert(order_id, strategy.to_string());
+    }
+
+    /// Strategy that sent an order, if any of its volume filled aggressively
+    pub fn strategy(&self, order_id: &str) -> Option<&str> {
+        self.orders.get(order_id).map(String::as_str)
+    }
+
+    pub fn trade(&mut self, trade: Arc<TradeMessage>, ours: bool) {
+        self.marks.insert(trade.product.clone(), trade.price);
+        if !ours {
+            return;
+        }
+        match self.orders.get(&trade.aggressor_order) {
+            Some(strategy) => {
+                let strategy = strategy.clone();
+                self.fill(&strategy, &trade);
+            }
+            None => self
+                .unattributed
+                .entry(trade.aggressor_order.clone())
+                .or_insert_with(|| (Instant::now(), Vec::new()))
+                .1
+                .push(trade),
+        }
+    }
+
+    /// Drops the trades whose order was never named by a response, such as when it was lost or sent by another process
+    pub fn expire(&mut self, now: Instant) {
+        self.unattributed.retain(|order_id, (seen, trades)| {
+            let expired = now.saturating_duration_since(*seen) >= Attribution::UNATTRIBUTED_TIMEOUT;
+            if expired {
+                warn!(
+                    order_id,
+                    trades = trades.len(),
+                    "Gave up attributing trades"
+                );
+            }
+            !expired
+        });
+    }
+
+    fn fill(&mut self, strategy: &str, trade: &TradeMessage) {
+        let side = if trade.trade_type == TradeType::SellAggressor {
+            Side::Sell
+        } else {
+            Side::Buy
+        };
+        self.strategies
+            .entry(strategy.to_string())
+            .or_default()
+            .fill(&trade.product, side, trade.price, trade.volume);
+    }
+}
+
+impl Display for Attribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut strategies: Vec<_> = self.strategies.iter().collect();
+        strategies.sort_by_key(|(name, _)| *name);
+        for (name, pnl) in strategies {
+            writeln!(
+                f,
+                "{name}: {} fills, {} lots, PnL {:.2}, positions {:?}",
+                pnl.fills,
+                pnl.volume,
+                pnl.pnl(&self.marks),
+                pnl.positions,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::username::Username;
+
+    static PRODUCT: &str = "F_SOP_APP0104T0950";
+
+    fn trade(aggressor_order: &str, trade_type: TradeType, price: i32) -> Arc<TradeMessage> {
+        Arc::new(TradeMessage {
+            product: PRODUCT.to_string(),
+            price: Price(price),
+            volume: Volume(10),
+            buyer: Username::KLiang,
+            seller: Username::PRao,
+            trade_type,
+            passive_order: String::from("0"),
+            passive_order_remaining: Volume(0),
+            aggressor_order: aggressor_order.to_string(),
+            sequence: 1,
+        })
+    }
+
+    struct Counter {
+        books: Vec<u64>,
+    }
+
+    impl Strategy for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn on_book(&mut self, book: Arc<Book>) -> Vec<OrderIntent> {
+            self.books.push(book.version);
+            Vec::new()
+        }
+    }
+
+    #[test]
+    fn test_book_slot_drops_superseded_books() {
+        let slot = BookSlot::default();
+        for (product, version) in [("1", 1), ("2", 2), ("1", 3), ("3", 4), ("1", 5)] {
+            slot.publish(Arc::new(Book {
+                product: product.to_string(),
+                version,
+                ..Default::default()
+            }));
+        }
+        slot.remove("3");
+        let mut strategy = Counter { books: Vec::new() };
+        dispatch(&mut strategy, slot.take(), Vec::new());
+        assert_eq!(strategy.books, vec![5, 2]);
+        assert!(slot.take().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Strategy counter is already registered")]
+    fn test_register_duplicate() {
+        let mut registry = StrategyRegistry::default();
+        registry.register(Box::new(Counter { books: Vec::new() }));
+        registry.register(Box::new(Counter { books: Vec::new() }));
+    }
+
+    #[test]
+    fn test_attribution() {
+        let mut attribution = Attribution::default();
+        attribution.order("arbitrage", String::from("1"));
+        attribution.trade(trade("1", TradeType::BuyAggressor, 1000), true);
+        // Seen on the feed before the response
+        attribution.trade(trade("2", TradeType::SellAggressor, 1100), true);
+        attribution.trade(trade("3", TradeType::SellAggressor, 1200), false);
+        assert!(!attribution.strategies.contains_key("making"));
+        attribution.order("making", String::from("2"));
+
+        let arbitrage = attribution.strategies.get("arbitrage").expect("Has fills");
+        assert_eq!(
+            arbitrage.positions,
+            HashMap::from([(PRODUCT.to_string(), 10)])
+        );
+        // Bought 10 at 10.00 now marked at 12.00
+        assert_eq!(arbitrage.pnl(&attribution.marks), 20.0);
+        let making = attribution.strategies.get("making").expect("Has fills");
+        assert_eq!(
+            making.positions,
+            HashMap::from([(PRODUCT.to_string(), -10)])
+        );
+        assert_eq!(making.pnl(&attribution.marks), -10.0);
+    }
+
+    #[test]
+    fn test_attribution_expiry() {
+        let mut attribution = Attribution::default();
+        attribution.trade(trade("1", TradeType::BuyAggressor, 1000), true);
+        let seen = Instant::now();
+        attribution.expire(seen);
+        assert_eq!(attribution.unattributed.len(), 1);
+
+        attribution.expire(seen + Attribution::UNATTRIBUTED_TIMEOUT);
+        assert!(attribution.unattributed.is_empty());
+        attribution.order("arbitrage", String::from("1"));
+        assert!(!attribution.strategies.contains_key("arbitrage"));
+    }
+}
@@ -0,0 +1,427 @@
+//! Trades printed on the feed, with rolling and session statistics per product.
+
+use crate::{
+    feed::{TradeMessage, TradeType},
+    types::{Price, Volume},
+    username::Username,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display},
+    io::Write,
+    time::{Duration, Instant},
+};
+use tracing::error;
+
+/// A trade as printed on the tape
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Print {
+    pub product: String,
+    pub price: Price,
+    pub volume: Volume,
+    pub buyer: Username,
+    pub seller: Username,
+    pub trade_type: TradeType,
+    pub sequence: u32,
+    /// When the trade arrived on the feed, None when it was replayed from `/recover`
+    #[serde(skip)]
+    pub received: Option<Instant>,
+}
+
+impl Print {
+    pub fn new(trade: &TradeMessage, received: Option<Instant>) -> Self {
+        Print {
+            product: trade.product.clone(),
+            price: trade.price,
+            volume: trade.volume,
+            buyer: trade.buyer.clone(),
+            seller: trade.seller.clone(),
+            trade_type: trade.trade_type.clone(),
+            sequence: trade.sequence,
+            received,
+        }
+    }
+
+    /// Whether the trade arrived live within the window before now
+    fn within(&self, window: Duration, now: Instant) -> bool {
+        self.received
+            .is_some_and(|received| now.saturating_duration_since(received) <= window)
+    }
+}
+
+/// Trading activity of a product over a window of time
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TradeStats {
+    pub trades: u32,
+    pub volume: u32,
+    pub buy_aggressor_volume: u32,
+    pub sell_aggressor_volume: u32,
+    /// Volume weighted average price in dollars
+    pub vwap: Option<f64>,
+    pub last: Option<Price>,
+}
+
+impl Display for TradeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} trades, {} lots ({} bought, {} sold by aggressors), vwap {}, last {:?}",
+            self.trades,
+            self.volume,
+            self.buy_aggressor_volume,
+            self.sell_aggressor_volume,
+            self.vwap
+                .map_or(String::from("-"), |vwap| format!("{vwap:.3}")),
+            self.last,
+        )
+    }
+}
+
+/// Running totals behind `TradeStats`
+#[derive(Debug, Default, Clone)]
+struct Totals {
+    stats: TradeStats,
+    /// Sum of price times volume in hundredths of a dollar
+    notional: i64,
+}
+
+impl Totals {
+    fn add(&mut self, print: &Print) {
+        let volume = print.volume.0;
+        self.stats.trades = self.stats.trades.saturating_add(1);
+        self.stats.volume = self.stats.volume.saturating_add(volume);
+        self.notional = self
+            .notional
+            .saturating_add(print.price.0 as i64 * volume as i64);
+        let aggressor_volume = match print.trade_type {
+            TradeType::BuyAggressor => Some(&mut self.stats.buy_aggressor_volume),
+            TradeType::SellAggressor => Some(&mut self.stats.sell_aggressor_volume),
+            TradeType::BrokerTrade => None,
+        };
+        if let Some(aggressor_volume) = aggressor_volume {
+            *aggressor_volume = aggressor_volume.saturating_add(volume);
+        }
+        self.stats.last = Some(print.price);
+    }
+
+    fn stats(&self) -> TradeStats {
+        TradeStats {
+            vwap: (self.stats.volume != 0)
+                .then(|| self.notional as f64 / self.stats.volume as f64 / 100.0),
+            ..self.stats.clone()
+        }
+    }
+}
+
+/// Trades of the session by product, kept outside the books so they stay cheap to clone
+///
+/// Only live prints within the retention are kept, the session totals and the export cover every trade.
+pub struct Tape {
+    /// Longest window statistics can be asked for
+    pub retention: Duration,
+    prints: HashMap<String, Vec<Print>>,
+    /// Broker trades are arranged off the book so they are kept apart from the prints
+    broker_prints: HashMap<String, Vec<Print>>,
+    session: HashMap<String, Totals>,
+    broker_session: HashMap<String, Totals>,
+    /// Where every print is written as a JSON line as soon as it is recorded
+    export: Option<Box<dyn Write + Send>>,
+}
+
+impl Default for Tape {
+    fn default() -> Self {
+        Tape::new(Tape::RETENTION)
+    }
+}
+
+impl Debug for Tape {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tape")
+            .field("retention", &self.retention)
+            .field("prints", &self.prints)
+            .field("broker_prints", &self.broker_prints)
+            .field("exporting", &self.export.is_some())
+            .finish()
+    }
+}
+
+impl Tape {
+    pub const RETENTION: Duration = Duration::from_secs(15 * 60);
+
+    pub fn new(retention: Duration) -> Self {
+        Tape {
+            retention,
+            prints: HashMap::new(),
+            broker_prints: HashMap::new(),
+            session: HashMap::new(),
+            broker_session: HashMap::new(),
+            export: None,
+        }
+    }
+
+    /// Write every print recorded from now on, broker trades included, as a JSON line in feed sequence order
+    pub fn export_to(&mut self, writer: impl Write + Send + 'static) {
+        self.export = Some(Box::new(writer));
+    }
+
+    /// Records a trade received at the given time, or replayed from `/recover` when None
+    pub fn record(&mut self, trade: &TradeMessage, received: Option<Instant>) {
+        let print = Print::new(trade, received);
+        self.write(&print);
+        let (prints, session) = match trade.trade_type {
+            TradeType::BrokerTrade => (&mut self.broker_prints, &mut self.broker_session),
+            TradeType::BuyAggressor | TradeType::SellAggressor => {
+                (&mut self.prints, &mut self.session)
+            }
+        };
+        session
+            .entry(trade.product.clone())
+            .or_default()
+            .add(&print);
+        let Some(received) = received else {
+            // Recovered trades have no place in any window
+            return;
+        };
+        let prints = prints.entry(trade.product.clone()).or_default();
+        prints.push(print);
+        // Drop expired prints in batches so each one is only moved a bounded number of times
+        let expired = prints.partition_point(|print| !print.within(self.retention, received));
+        if expired * 2 >= prints.len() {
+            prints.drain(..expired);
+        }
+    }
+
+    fn write(&mut self, print: &Print) {
+        let Some(writer) = self.export.as_mut() else {
+            return;
+        };
+        let written = serde_json::to_writer(&mut *writer, print)
+            .map_err(std::io::Error::from)
+            .and_then(|()| writeln!(writer));
+        if let Err(err) = written {
+            error!(error = %err, "Failed to export the tape, no longer exporting");
+            self.export = None;
+        }
+    }
+
+    /// Flushes the export
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        match self.export.as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Live prints of a product in the order they arrived, at least those within the retention
+    pub fn prints(&self, product: &str) -> &[Print] {
+        self.prints.get(product).map_or(&[], Vec::as_slice)
+    }
+
+    /// Live broker trades of a product in the order they arrived, at least those within the retention
+    pub fn broker_prints(&self, product: &str) -> &[Print] {
+        self.broker_prints.get(product).map_or(&[], Vec::as_slice)
+    }
+
+    /// Statistics of the trades on the book received live within the window before now, up to the retention
+    pub fn stats(&self, product: &str, window: Duration, now: Instant) -> TradeStats {
+        self.window_stats(&self.prints, product, window, now)
+    }
+
+    /// Statistics of the broker trades received live within the window before now, up to the retention
+    pub fn broker_stats(&self, product: &str, window: Duration, now: Instant) -> TradeStats {
+        self.window_stats(&self.broker_prints, product, window, now)
+    }
+
+    fn window_stats(
+        &self,
+        prints: &HashMap<String, Vec<Print>>,
+        product: &str,
+        window: Duration,
+        now: Instant,
+    ) -> TradeStats {
+        let window = window.min(self.retention);
+        let prints = prints.get(product).map_or(&[][..], Vec::as_slice);
+        let start = prints.partition_point(|print| !print.within(window, now));
+        let mut totals = Totals::default();
+        for print in prints[start..].iter() {
+            totals.add(print);
+        }
+        totals.stats()
+    }
+
+    /// Statistics of every trade on the book of the session, recovered ones included, ordered by product
+    pub fn session_stats(&self) -> Vec<(&str, TradeStats)> {
+        Tape::sorted_stats(&self.session)
+    }
+
+    /// Statistics of every broker trade of the session, recovered ones included, ordered by product
+    pub fn session_broker_stats(&self) -> Vec<(&str, TradeStats)> {
+        Tape::sorted_stats(&self.broker_session)
+    }
+
+    fn sorted_stats(session: &HashMap<String, Totals>) -> Vec<(&str, TradeStats)> {
+        let mut stats: Vec<_> = session
+            .iter()
+            .map(|(product, totals)| (product.as_str(), totals.stats()))
+            .collect();
+        stats.sort_by_key(|(product, _)| *product);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static PRODUCT: &str = "F_SOP_APP0104T0950";
+
+    fn trade(price: i32, volume: u32, trade_type: TradeType, sequence: u32) -> TradeMessage {
+        TradeMessage {
+            product: PRODUCT.to_string(),
+            price: Price(price),
+            volume: Volume(volume),
+            buyer: Username::KLiang,
+            seller: Username::PRao,
+            trade_type,
+            passive_order: String::from("1"),
+            passive_order_remaining: Volume(0),
+            aggressor_order: String::from("2"),
+            sequence,
+        }
+    }
+
+    #[test]
+    fn test_stats_window() {
+        let mut tape = Tape::default();
+        let start = Instant::now();
+        tape.record(&trade(1000, 10, TradeType::BuyAggressor, 1), Some(start));
+        tape.record(
+            &trade(1100, 30, TradeType::SellAggressor, 2),
+            Some(start + Duration::from_secs(5)),
+        );
+        tape.record(
+            &trade(1200, 10, TradeType::BrokerTrade, 3),
+            Some(start + Duration::from_secs(8)),
+        );
+        let now = start + Duration::from_secs(10);
+
+        assert_eq!(
+            tape.stats(PRODUCT, Duration::from_secs(60), now),
+            TradeStats {
+                trades: 2,
+                volume: 40,
+                buy_aggressor_volume: 10,
+                sell_aggressor_volume: 30,
+                vwap: Some(10.75),
+                last: Some(Price(1100)),
+            },
+        );
+        assert_eq!(
+            tape.stats(PRODUCT, Duration::from_secs(5), now),
+            TradeStats {
+                trades: 1,
+                volume: 30,
+                buy_aggressor_volume: 0,
+                sell_aggressor_volume: 30,
+                vwap: Some(11.0),
+                last: Some(Price(1100)),
+            },
+        );
+        // Broker trades count towards neither side's aggressive volume
+        assert_eq!(
+            tape.broker_stats(PRODUCT, Duration::from_secs(60), now),
+            TradeStats {
+                trades: 1,
+                volume: 10,
+                buy_aggressor_volume: 0,
+                sell_aggressor_volume: 0,
+                vwap: Some(12.0),
+                last: Some(Price(1200)),
+            },
+        );
+        assert_eq!(tape.prints(PRODUCT).len(), 2);
+        assert_eq!(tape.broker_prints(PRODUCT).len(), 1);
+        assert_eq!(
+            tape.stats("other", Duration::from_secs(5), now),
+            TradeStats::default(),
+        );
+    }
+
+    #[test]
+    fn test_recovered_and_expired_prints() {
+        let mut tape = Tape::new(Duration::from_secs(60));
+        let start = Instant::now();
+        // Replayed from the recovery snapshot
+        tape.record(&trade(850, 20, TradeType::BuyAggressor, 1), None);
+        tape.record(&trade(1000, 10, TradeType::BuyAggressor, 2), Some(start));
+        assert_eq!(tape.prints(PRODUCT).len(), 1);
+        assert_eq!(
+            tape.stats(PRODUCT, Duration::MAX, start).volume,
+            10,
+            "Recovered trades are not in any window",
+        );
+
+        let later = start + Duration::from_secs(61);
+        tape.record(&trade(1100, 30, TradeType::SellAggressor, 3), Some(later));
+        assert_eq!(tape.prints(PRODUCT).len(), 1);
+        assert_eq!(
+            tape.stats(PRODUCT, Duration::MAX, later),
+            TradeStats {
+                trades: 1,
+                volume: 30,
+                buy_aggressor_volume: 0,
+                sell_aggressor_volume: 30,
+                vwap: Some(11.0),
+                last: Some(Price(1100)),
+            },
+        );
+        assert_eq!(
+            tape.session_stats(),
+            vec![(
+                PRODUCT,
+                TradeStats {
+                    trades: 3,
+                    volume: 60,
+                    buy_aggressor_volume: 30,
+                    sell_aggressor_volume: 30,
+                    vwap: Some(10.0),
+                    last: Some(Price(1100)),
+                },
+            )],
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        let path = std::env::temp_dir().join(format!("bomex_{}_tape.jsonl", std::process::id()));
+        let file = std::fs::File::create(&path).expect("Failed to create tape");
+        let mut tape = Tape::default();
+        tape.export_to(file);
+        let now = Instant::now();
+        tape.record(
+            &TradeMessage {
+                product: String::from("other"),
+                ..trade(1050, 5, TradeType::SellAggressor, 1)
+            },
+            None,
+        );
+        tape.record(&trade(1000, 10, TradeType::BuyAggressor, 2), Some(now));
+        tape.record(&trade(1020, 3, TradeType::BrokerTrade, 3), Some(now));
+        tape.flush().expect("Failed to flush tape");
+        let exported = std::fs::read_to_string(&path).expect("Failed to read tape");
+        std::fs::remove_file(path).expect("Failed to remove tape");
+        assert_eq!(
+            exported,
+            concat!(
+                r#"{"product":"other","price":10.5,"volume":5,"buyer":"kliang","seller":"prao","tradeType":"SELL_AGGRESSOR","sequence":1}"#,
+                "\n",
+                r#"{"product":"F_SOP_APP0104T0950","price":10.0,"volume":10,"buyer":"kliang","seller":"prao","tradeType":"BUY_AGGRESSOR","sequence":2}"#,
+                "\n",
+                r#"{"product":"F_SOP_APP0104T0950","price":10.2,"volume":3,"buyer":"kliang","seller":"prao","tradeType":"BROKER_TRADE","sequence":3}"#,
+                "\n",
+            ),
+        );
+    }
+}
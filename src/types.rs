@@ -1,6 +1,8 @@
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+//! Prices, volumes and sides shared by the feed, books and orders.
+
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
-    fmt::Debug,
+    fmt::{Debug, Display},
     ops::{Add, AddAssign, Sub, SubAssign},
 };
 
@@ -11,53 +13,108 @@ macro_rules! to_underlying {
     };
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// Side of an order or of the book
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum Side {
     Buy,
     Sell,
 }
 
-#[derive(Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Price(pub u16);
+/// Arithmetic or a conversion that does not fit in a price or volume
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeError {
+    /// The result of an operation, named by the string, is out of range
+    Overflow(&'static str),
+    /// A price in dollars that is not finite or too large to store
+    InvalidPrice(f64),
+}
+
+impl Display for TypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TypeError::Overflow(operation) => write!(f, "{operation} overflowed"),
+            TypeError::InvalidPrice(price) => write!(f, "{price} is not a valid price"),
+        }
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+/// A price in cents, sent over the wire in dollars, negative for products that allow it
+#[derive(Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Price(pub i32);
+
+impl Price {
+    pub const MIN: Self = Price(i32::MIN);
+    pub const MAX: Self = Price(i32::MAX);
+
+    /// Converts dollars to the nearest cent
+    pub fn from_dollars(dollars: f64) -> Result<Self, TypeError> {
+        let cents = (dollars * 100.0).round();
+        if !cents.is_finite() || cents < i32::MIN as f64 || cents > i32::MAX as f64 {
+            return Err(TypeError::InvalidPrice(dollars));
+        }
+        Ok(Price(cents as i32))
+    }
+
+    pub fn dollars(self) -> f64 {
+        to_underlying!(self) as f64 / 100.0
+    }
+
+    pub fn checked_add(self, rhs: Price) -> Result<Price, TypeError> {
+        to_underlying!(self)
+            .checked_add(to_underlying!(rhs))
+            .map(Price)
+            .ok_or(TypeError::Overflow("Price addition"))
+    }
+
+    pub fn checked_sub(self, rhs: Price) -> Result<Price, TypeError> {
+        to_underlying!(self)
+            .checked_sub(to_underlying!(rhs))
+            .map(Price)
+            .ok_or(TypeError::Overflow("Price subtraction"))
+    }
+}
 
 impl Debug for Price {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", to_underlying!(self) as f64 / 100.0)
+        write!(f, "{}", self.dollars())
     }
 }
 
 impl Sub for Price {
     type Output = Price;
     fn sub(self, rhs: Self) -> Self::Output {
-        self - to_underlying!(rhs)
+        self.checked_sub(rhs).expect("Price out of range")
     }
 }
 
-impl Sub<u16> for Price {
+impl Sub<i32> for Price {
     type Output = Price;
-    fn sub(self, rhs: u16) -> Self::Output {
-        Price(to_underlying!(self) - rhs)
+    fn sub(self, rhs: i32) -> Self::Output {
+        self - Price(rhs)
     }
 }
 
-impl Add<u16> for Price {
+impl Add<i32> for Price {
     type Output = Price;
-    fn add(self, rhs: u16) -> Self::Output {
-        Price(to_underlying!(self) + rhs)
+    fn add(self, rhs: i32) -> Self::Output {
+        self + Price(rhs)
     }
 }
 
 impl Add<Price> for Price {
     type Output = Price;
     fn add(self, rhs: Self) -> Self::Output {
-        self + to_underlying!(rhs)
+        self.checked_add(rhs).expect("Price out of range")
     }
 }
 
-impl From<f64> for Price {
-    fn from(price: f64) -> Self {
-        Price((price * 100.0) as u16)
+impl TryFrom<f64> for Price {
+    type Error = TypeError;
+    fn try_from(dollars: f64) -> Result<Self, Self::Error> {
+        Price::from_dollars(dollars)
     }
 }
 
@@ -66,8 +123,7 @@ impl Serialize for Price {
     where
         S: Serializer,
     {
-        let price = to_underlying!(self) as f64 / 100.0;
-        price.serialize(serializer)
+        self.dollars().serialize(serializer)
     }
 }
 
@@ -77,15 +133,43 @@ impl<'de> Deserialize<'de> for Price {
         D: Deserializer<'de>,
     {
         let price: f64 = Deserialize::deserialize(deserializer)?;
-        Ok(price.into())
+        Price::from_dollars(price).map_err(D::Error::custom)
     }
 }
 
+/// A number of lots
 #[derive(Eq, PartialOrd, Ord, Default, Clone, Copy, Serialize, Deserialize)]
-pub struct Volume(pub u16);
+pub struct Volume(pub u32);
 
 impl Volume {
-    pub const MAX: Self = Volume(u16::MAX);
+    pub const MAX: Self = Volume(u32::MAX);
+
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        Volume(to_underlying!(self).saturating_add(to_underlying!(rhs)))
+    }
+
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Volume(to_underlying!(self).saturating_sub(to_underlying!(rhs)))
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Result<Self, TypeError> {
+        to_underlying!(self)
+            .checked_add(to_underlying!(rhs))
+            .map(Volume)
+            .ok_or(TypeError::Overflow("Volume addition"))
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Result<Self, TypeError> {
+        to_underlying!(self)
+            .checked_sub(to_underlying!(rhs))
+            .map(Volume)
+            .ok_or(TypeError::Overflow("Volume subtraction"))
+    }
+
+    /// The volume as a signed position change
+    pub fn signed(self) -> Result<i32, TypeError> {
+        i32::try_from(to_underlying!(self)).map_err(|_| TypeError::Overflow("Volume to position"))
+    }
 }
 
 impl Debug for Volume {
@@ -100,14 +184,14 @@ impl PartialEq for Volume {
     }
 }
 
-impl PartialOrd<u16> for Volume {
-    fn partial_cmp(&self, other: &u16) -> Option<std::cmp::Ordering> {
+impl PartialOrd<u32> for Volume {
+    fn partial_cmp(&self, other: &u32) -> Option<std::cmp::Ordering> {
         Some(to_underlying!(self).cmp(other))
     }
 }
 
-impl PartialEq<u16> for Volume {
-    fn eq(&self, other: &u16) -> bool {
+impl PartialEq<u32> for Volume {
+    fn eq(&self, other: &u32) -> bool {
         to_underlying!(self) == *other
     }
 }
@@ -115,44 +199,118 @@ impl PartialEq<u16> for Volume {
 impl Sub for Volume {
     type Output = Volume;
     fn sub(self, rhs: Self) -> Self::Output {
-        Volume(to_underlying!(self) - to_underlying!(rhs))
+        self.checked_sub(rhs).expect("Volume out of range")
     }
 }
 
-impl Sub<Volume> for i16 {
-    type Output = i16;
+impl Sub<Volume> for i32 {
+    type Output = i32;
     fn sub(self, rhs: Volume) -> Self::Output {
-        self - to_underlying!(rhs) as i16
+        rhs.signed()
+            .ok()
+            .and_then(|rhs| self.checked_sub(rhs))
+            .expect("Position out of range")
     }
 }
 
-impl Add<Volume> for i16 {
-    type Output = i16;
+impl Add<Volume> for i32 {
+    type Output = i32;
     fn add(self, rhs: Volume) -> Self::Output {
-        self + to_underlying!(rhs) as i16
+        rhs.signed()
+            .ok()
+            .and_then(|rhs| self.checked_add(rhs))
+            .expect("Position out of range")
     }
 }
 
 impl AddAssign for Volume {
     fn add_assign(&mut self, rhs: Self) {
-        to_underlying!(self) += to_underlying!(rhs);
+        *self = self.checked_add(rhs).expect("Volume out of range");
     }
 }
 
 impl SubAssign for Volume {
     fn sub_assign(&mut self, rhs: Self) {
-        to_underlying!(self) -= to_underlying!(rhs);
+        *self = *self - rhs;
     }
 }
 
-impl AddAssign<Volume> for i16 {
+impl AddAssign<Volume> for i32 {
     fn add_assign(&mut self, rhs: Volume) {
-        *self += to_underlying!(rhs) as i16;
+        *self = *self + rhs;
     }
 }
 
-impl SubAssign<Volume> for i16 {
+impl SubAssign<Volume> for i32 {
     fn sub_assign(&mut self, rhs: Volume) {
-        *self -= to_underlying!(rhs) as i16;
+        *self = *self - rhs;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_price_rounding() {
+        // 18.98 * 100.0 is 1897.9999999999998
+        let parse = |json| serde_json::from_str::<Price>(json).expect("Valid price");
+        assert_eq!(parse("18.98"), Price(1898));
+        assert_eq!(parse("-0.07"), Price(-7));
+        assert_eq!(parse("1000.5"), Price(100050));
+        assert!(serde_json::from_str::<Price>("1e300").is_err());
+        assert!(Price::from_dollars(f64::NAN).is_err());
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        assert_eq!(Price(1000).checked_sub(Price(1500)), Ok(Price(-500)));
+        assert_eq!(
+            Price::MAX.checked_add(Price(1)),
+            Err(TypeError::Overflow("Price addition")),
+        );
+        assert_eq!(
+            Volume(1).checked_sub(Volume(2)),
+            Err(TypeError::Overflow("Volume subtraction")),
+        );
+        assert_eq!(Volume(1).saturating_sub(Volume(2)), Volume(0));
+        assert_eq!(
+            Volume::MAX.signed(),
+            Err(TypeError::Overflow("Volume to position")),
+        );
+        let mut position = -5;
+        position += Volume(7);
+        assert_eq!(position, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Volume out of range")]
+    fn test_volume_underflow_panics() {
+        let mut volume = Volume(1);
+        volume -= Volume(2);
+    }
+
+    proptest! {
+        #[test]
+        fn price_round_trips(cents in any::<i32>()) {
+            let price = Price(cents);
+            let json = serde_json::to_string(&price).expect("Price serializes");
+            prop_assert_eq!(serde_json::from_str::<Price>(&json).expect("Valid price"), price);
+        }
+
+        #[test]
+        fn decimal_prices_parse_exactly(cents in -10_000_000i32..10_000_000) {
+            let sign = if cents < 0 { "-" } else { "" };
+            let json = format!("{sign}{}.{:02}", cents.unsigned_abs() / 100, cents.unsigned_abs() % 100);
+            prop_assert_eq!(serde_json::from_str::<Price>(&json).expect("Valid price"), Price(cents));
+        }
+
+        #[test]
+        fn volume_round_trips(lots in any::<u32>()) {
+            let volume = Volume(lots);
+            let json = serde_json::to_string(&volume).expect("Volume serializes");
+            prop_assert_eq!(serde_json::from_str::<Volume>(&json).expect("Valid volume"), volume);
+        }
     }
 }
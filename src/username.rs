@@ -1,5 +1,8 @@
+//! Traders on the exchange.
+
 use serde::{Deserialize, Serialize};
 
+/// Every trader on the exchange
 #[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Username {
@@ -0,0 +1,291 @@
+//! Books disabled for trading and the timeouts after which they are reenabled or reported.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DisableReason {
+    /// An order has been sent and its response has not arrived yet
+    OrderInFlight,
+    /// An order has been filled and its trades have not shown up on the feed yet
+    AwaitingTrade,
+    /// An order failed after it may have reached the exchange
+    Unconfirmed,
+    /// The best bid is at or above the best ask, so the book cannot be trusted
+    Crossed,
+    /// A feed message could not be applied, so the book no longer matches the exchange until it is recovered
+    Inconsistent,
+}
+
+impl DisableReason {
+    /// Whether the book can be safely re-enabled once the deadline passes, otherwise the disable is escalated
+    fn reenable_on_expiry(&self) -> bool {
+        !matches!(
+            self,
+            DisableReason::OrderInFlight | DisableReason::Crossed | DisableReason::Inconsistent
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Disabled {
+    pub since: Instant,
+    pub timeout: Duration,
+    pub deadline: Instant,
+    /// Number of times the deadline passed without the book being safe to re-enable
+    pub escalations: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct WatchdogConfig {
+    /// How often deadlines are checked when no feed messages arrive
+    pub interval: Duration,
+    /// How long to wait for the trades of a filled order to show up on the feed
+    pub awaiting_trade: Duration,
+    /// How long a book may stay crossed before it is reported again
+    pub crossed: Duration,
+    /// How long a book that failed to apply a feed message stays disabled before it is reported again
+    pub inconsistent: Duration,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            interval: Duration::from_millis(250),
+            awaiting_trade: Duration::from_secs(5),
+            crossed: Duration::from_secs(5),
+            inconsistent: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A disable whose deadline has passed
+#[derive(Debug, PartialEq)]
+pub struct Expired {
+    pub product: String,
+    pub reason: DisableReason,
+    /// Whether the book was re-enabled for this reason or the disable was escalated and extended
+    pub reenabled: bool,
+    pub escalations: u32,
+}
+
+/// Tracks why each book is disabled, a book is enabled when it has no disable reasons
+#[derive(Debug, Default)]
+pub struct BookStates {
+    disabled: HashMap<String, HashMap<DisableReason, Disabled>>,
+}
+
+impl BookStates {
+    pub fn is_enabled(&self, product: &str) -> bool {
+        self.disabled
+            .get(product)
+            .is_none_or(|reasons| reasons.is_empty())
+    }
+
+    /// Disable a book until it is enabled for the same reason or the timeout passes, keeps the original deadline if already disabled for this reason
+    pub fn disable(&mut self, product: &str, reason: DisableReason, timeout: Duration) {
+        let now = Instant::now();
+        self.disabled
+            .entry(product.to_string())
+            .or_default()
+            .entry(reason)
+            .or_insert(Disabled {
+                since: now,
+                timeout,
+                deadline: now + timeout,
+                escalations: 0,
+            });
+    }
+
+    pub fn enable(&mut self, product: &str, reason: DisableReason) {
+        if let Some(reasons) = self.disabled.get_mut(product) {
+            reasons.remove(&reason);
+            if reasons.is_empty() {
+                self.disabled.remove(product);
+            }
+        }
+    }
+
+    /// Forget about a book that no longer trades
+    pub fn remove(&mut self, product: &str) {
+        self.disabled.remove(product);
+    }
+
+    /// Re-enable or escalate every disable whose deadline has passed
+    pub fn expire(&mut self, now: Instant) -> Vec<Expired> {
+        let mut expired = Vec::new();
+        for (product, reasons) in self.disabled.iter_mut() {
+            reasons.retain(|&reason, disabled| {
+                if disabled.deadline > now {
+                    return true;
+                }
+                let reenabled = reason.reenable_on_expiry();
+                if !reenabled {
+                    disabled.escalations += 1;
+                    disabled.deadline = now + disabled.timeout;
+                }
+                expired.push(Expired {
+                    product: product.clone(),
+                    reason,
+                    reenabled,
+                    escalations: disabled.escalations,
+                });
+                !reenabled
+            });
+        }
+        self.disabled.retain(|_, reasons| !reasons.is_empty());
+        expired
+    }
+
+    /// Every disabled book along with why and until when, ordered by product, then since when and why
+    pub fn disabled_books(&self) -> Vec<(&str, DisableReason, &Disabled)> {
+        let mut disabled: Vec<_> = self
+            .disabled
+            .iter()
+            .flat_map(|(product, reasons)| {
+                reasons
+                    .iter()
+                    .map(|(&reason, disabled)| (product.as_str(), reason, disabled))
+            })
+            .collect();
+        disabled.sort_by(|a, b| {
+            a.0.cmp(b.0)
+                .then(a.2.since.cmp(&b.2.since))
+                .then(a.1.cmp(&b.1))
+        });
+        disabled
+    }
+}
+
+impl Display for BookStates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let now = Instant::now();
+        for (product, reason, disabled) in self.disabled_books() {
+            writeln!(
+                f,
+                "{product}: {reason:?} for {:?}, deadline in {:?}, escalated {} times",
+                now.saturating_duration_since(disabled.since),
+                disabled.deadline.saturating_duration_since(now),
+                disabled.escalations,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static PRODUCT: &str = "F_SOP_APP0104T0950";
+
+    #[test]
+    fn test_disable_and_enable() {
+        let mut states = BookStates::default();
+        assert!(states.is_enabled(PRODUCT));
+
+        states.disable(
+            PRODUCT,
+            DisableReason::OrderInFlight,
+            Duration::from_secs(1),
+        );
+        states.disable(PRODUCT, DisableReason::Unconfirmed, Duration::from_secs(1));
+        assert!(!states.is_enabled(PRODUCT));
+        assert_eq!(
+            states
+                .disabled_books()
+                .iter()
+                .map(|(product, reason, _)| (*product, *reason))
+                .collect::<Vec<_>>(),
+            vec![
+                (PRODUCT, DisableReason::OrderInFlight),
+                (PRODUCT, DisableReason::Unconfirmed),
+            ],
+        );
+
+        states.enable(PRODUCT, DisableReason::OrderInFlight);
+        assert!(!states.is_enabled(PRODUCT));
+        states.enable(PRODUCT, DisableReason::Unconfirmed);
+        assert!(states.is_enabled(PRODUCT));
+        assert_eq!(states.disabled_books(), vec![]);
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut states = BookStates::default();
+        states.disable(PRODUCT, DisableReason::AwaitingTrade, Duration::ZERO);
+        states.disable(PRODUCT, DisableReason::OrderInFlight, Duration::ZERO);
+        states.disable("other", DisableReason::Unconfirmed, Duration::from_secs(60));
+
+        let mut expired = states.expire(Instant::now());
+        expired.sort_by_key(|expired| expired.reenabled);
+        assert_eq!(
+            expired,
+            vec![
+                Expired {
+                    product: PRODUCT.to_string(),
+                    reason: DisableReason::OrderInFlight,
+                    reenabled: false,
+                    escalations: 1,
+                },
+                Expired {
+                    product: PRODUCT.to_string(),
+                    reason: DisableReason::AwaitingTrade,
+                    reenabled: true,
+                    escalations: 0,
+                },
+            ],
+        );
+        assert_eq!(
+            states
+                .disabled_books()
+                .iter()
+                .map(|(product, reason, disabled)| (*product, *reason, disabled.escalations))
+                .collect::<Vec<_>>(),
+            vec![
+                (PRODUCT, DisableReason::OrderInFlight, 1),
+                ("other", DisableReason::Unconfirmed, 0),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_disabled_books_order() {
+        let mut states = BookStates::default();
+        let now = Instant::now();
+        let disabled = Disabled {
+            since: now,
+            timeout: Duration::ZERO,
+            deadline: now,
+            escalations: 0,
+        };
+        states.disabled.insert(
+            PRODUCT.to_string(),
+            [
+                DisableReason::Crossed,
+                DisableReason::Unconfirmed,
+                DisableReason::OrderInFlight,
+                DisableReason::AwaitingTrade,
+            ]
+            .into_iter()
+            .map(|reason| (reason, disabled.clone()))
+            .collect(),
+        );
+        assert_eq!(
+            states
+                .disabled_books()
+                .iter()
+                .map(|(_, reason, _)| *reason)
+                .collect::<Vec<_>>(),
+            vec![
+                DisableReason::OrderInFlight,
+                DisableReason::AwaitingTrade,
+                DisableReason::Unconfirmed,
+                DisableReason::Crossed,
+            ],
+        );
+    }
+}